@@ -0,0 +1,1043 @@
+use crate::build::OVERLAYFS_WHITEOUT_OPAQUE;
+use crate::error::{MergeTreeError, Result};
+use crate::meta::{FileType, NodeMeta};
+use crate::scan::ScannedDirs;
+use crate::tree::{
+    ChildOrder, FileSystemTree, HardlinkKey, Overlay, ScanOption, TreeNode, WhiteoutSpec, XAttrs,
+};
+use nix::sys::stat;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::ffi::{OsStr, OsString};
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Component, Path, PathBuf};
+use tar::{Archive, Entry, EntryType};
+use trees::Tree;
+
+/// Prefix of PAX records holding xattrs, as written by GNU tar and bsdtar
+pub const PAX_XATTR_PREFIX: &str = "SCHILY.xattr.";
+
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+
+/// Algorithm of digests which can be verified
+pub const DIGEST_ALGORITHM: &str = "sha256";
+
+/// Expected digests of a layer archive, verified while the layer is read
+#[derive(Clone, Debug, Default)]
+pub struct LayerDigests {
+    /// Digest of the archive as it is stored, maybe compressed, like the
+    /// digest of a layer descriptor
+    pub blob: Option<String>,
+    /// Digest of the uncompressed tar, like rootfs.diff_ids of image config
+    pub diff_id: Option<String>,
+}
+
+/// Digest of data like "sha256:<hex>"
+pub fn sha256_digest(data: &[u8]) -> String {
+    format!("{}:{:x}", DIGEST_ALGORITHM, Sha256::digest(data))
+}
+
+/// Check digest of data read from path, like a manifest or config blob
+pub fn verify_data(path: &Path, expected: &str, data: &[u8]) -> Result<()> {
+    check_digest_algorithm(path, expected)?;
+    verify_digest(path, expected, sha256_digest(data))
+}
+
+fn verify_digest(path: &Path, expected: &str, actual: String) -> Result<()> {
+    if expected != actual {
+        return Err(MergeTreeError::DigestMismatch {
+            path: path.to_path_buf(),
+            expected: expected.to_string(),
+            actual,
+        });
+    }
+    Ok(())
+}
+
+// only sha256 is known, a digest of another algorithm can't be verified
+fn check_digest_algorithm(path: &Path, digest: &str) -> Result<()> {
+    match digest.split_once(':') {
+        Some((algorithm, _)) if algorithm == DIGEST_ALGORITHM => Ok(()),
+        _ => Err(MergeTreeError::InvalidImage {
+            path: path.to_path_buf(),
+            reason: format!("unsupported digest {}", digest),
+        }),
+    }
+}
+
+// hash every byte read through it
+struct DigestReader<R> {
+    inner: R,
+    hasher: Sha256,
+}
+
+impl<R: Read> DigestReader<R> {
+    fn new(inner: R) -> Self {
+        DigestReader {
+            inner,
+            hasher: Sha256::new(),
+        }
+    }
+
+    // read the rest of the stream and return the digest of all of it
+    fn finish(mut self) -> io::Result<String> {
+        io::copy(&mut self, &mut io::sink())?;
+        Ok(format!("{}:{:x}", DIGEST_ALGORITHM, self.hasher.finalize()))
+    }
+}
+
+impl<R: Read> Read for DigestReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.hasher.update(&buf[..n]);
+        Ok(n)
+    }
+}
+
+/// Compression of a layer archive, like the media types of OCI layers
+/// "tar", "tar+gzip" and "tar+zstd"
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Compression {
+    None,
+    Gzip,
+    Zstd,
+}
+
+impl Compression {
+    /// Detect compression by magic bytes at the start of the archive, any
+    /// other bytes are taken as plain tar
+    pub fn detect(magic: &[u8]) -> Self {
+        if magic.starts_with(GZIP_MAGIC) {
+            Compression::Gzip
+        } else if magic.starts_with(ZSTD_MAGIC) {
+            Compression::Zstd
+        } else {
+            Compression::None
+        }
+    }
+}
+
+/// Stream of the tar archive in a maybe compressed layer, decompressed while
+/// it is read
+pub fn decompress<'a, R: BufRead + 'a>(mut reader: R) -> io::Result<Box<dyn Read + 'a>> {
+    let compression = Compression::detect(reader.fill_buf()?);
+    log::debug!("layer compression {:?}", compression);
+    Ok(match compression {
+        Compression::None => Box::new(reader),
+        // a gzip layer may be several members concatenated
+        Compression::Gzip => Box::new(flate2::bufread::MultiGzDecoder::new(reader)),
+        Compression::Zstd => Box::new(zstd::stream::read::Decoder::with_buffer(reader)?),
+    })
+}
+
+// Entries of an archive collected by dir before the tree is assembled, with
+// the same layout as scanned dirs. Dir 0 is the root.
+struct ArchiveDirs {
+    dirs: Vec<Vec<(TreeNode, Option<usize>)>>,
+    // position of a child in its dir by dir id and name
+    index: HashMap<(usize, OsString), usize>,
+    // inode of the next hardlink group, archives have no inodes
+    next_ino: u64,
+}
+
+impl ArchiveDirs {
+    fn new() -> Self {
+        ArchiveDirs {
+            dirs: vec![Vec::new()],
+            index: HashMap::new(),
+            next_ino: 1,
+        }
+    }
+
+    // find the dir id of an entry's parent, missing parents are added as
+    // implicit dirs since archives don't need to have an entry for every dir.
+    // A later entry of the dir replaces it by put.
+    fn parent_dir(
+        &mut self,
+        names: &[&OsStr],
+        layer_path: &Path,
+        overlay: Overlay,
+    ) -> Result<usize> {
+        let mut dir = 0;
+        let mut source = layer_path.to_path_buf();
+        for name in names {
+            source.push(name);
+            let key = (dir, name.to_os_string());
+            if !self.index.contains_key(&key) {
+                let mut node = TreeNode::new(
+                    name.to_os_string(),
+                    NodeMeta::new(FileType::Directory, 0o755),
+                    overlay,
+                    source.clone(),
+                );
+                node.implicit = true;
+                self.put(dir, node);
+            }
+            dir = match self.dirs[dir][self.index[&key]].1 {
+                Some(id) => id,
+                None => {
+                    return Err(MergeTreeError::InvalidArchive {
+                        path: source,
+                        reason: "parent of entry is not a directory".to_string(),
+                    })
+                }
+            };
+        }
+        Ok(dir)
+    }
+
+    // add node to dir, an entry with the same name is replaced by it like
+    // extracting the archive, and a dir replacing a dir keeps its children
+    fn put(&mut self, dir: usize, node: TreeNode) {
+        let key = (dir, node.name.clone());
+        let position = self.index.get(&key).copied();
+        let child_id = match position.and_then(|position| self.dirs[dir][position].1) {
+            Some(id) if node.is_directory() => Some(id),
+            _ if node.is_directory() => {
+                self.dirs.push(Vec::new());
+                Some(self.dirs.len() - 1)
+            }
+            _ => None,
+        };
+        match position {
+            Some(position) => self.dirs[dir][position] = (node, child_id),
+            None => {
+                self.index.insert(key, self.dirs[dir].len());
+                self.dirs[dir].push((node, child_id));
+            }
+        }
+    }
+
+    // node of a hardlink target added before, with the hardlink key set
+    fn link_target(&mut self, names: &[&OsStr]) -> Option<TreeNode> {
+        let (name, parents) = names.split_last()?;
+        let mut dir = 0;
+        for parent in parents {
+            let position = *self.index.get(&(dir, parent.to_os_string()))?;
+            dir = self.dirs[dir][position].1?;
+        }
+        let position = *self.index.get(&(dir, name.to_os_string()))?;
+        let (target, _) = &mut self.dirs[dir][position];
+        if target.is_directory() {
+            return None;
+        }
+        if target.hardlink.is_none() {
+            target.hardlink = Some(HardlinkKey {
+                layer: 0,
+                dev: 0,
+                ino: self.next_ino,
+            });
+            self.next_ino += 1;
+        }
+        Some(target.clone())
+    }
+}
+
+// names of an entry path from the archive root, "./" and "/" are dropped
+fn entry_names<'a>(path: &'a Path, source: &Path) -> Result<Vec<&'a OsStr>> {
+    let mut names = Vec::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(name) => names.push(name),
+            Component::CurDir | Component::RootDir | Component::Prefix(_) => {}
+            Component::ParentDir => {
+                return Err(MergeTreeError::InvalidArchive {
+                    path: source.to_path_buf(),
+                    reason: "entry path goes out of the layer with \"..\"".to_string(),
+                })
+            }
+        }
+    }
+    Ok(names)
+}
+
+// PAX time is decimal seconds with optional fraction, e.g. "1634567890.5"
+fn parse_pax_time(value: &str) -> Option<(i64, i64)> {
+    let (secs, frac) = match value.split_once('.') {
+        Some((secs, frac)) => (secs, frac),
+        None => (value, ""),
+    };
+    let secs: i64 = secs.parse().ok()?;
+    if !frac.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let digits: String = frac.chars().chain("000000000".chars()).take(9).collect();
+    let nsec: i64 = digits.parse().ok()?;
+    // "-1.5" is 1.5 seconds before epoch, nsec is always positive
+    if value.starts_with('-') && nsec > 0 {
+        return Some((secs - 1, 1_000_000_000 - nsec));
+    }
+    Some((secs, nsec))
+}
+
+fn entry_meta<R: Read>(entry: &Entry<R>, file_type: FileType, source: &Path) -> Result<NodeMeta> {
+    let header = entry.header();
+    let invalid = |e| MergeTreeError::InvalidArchive {
+        path: source.to_path_buf(),
+        reason: format!("bad header: {}", e),
+    };
+    let mut meta = NodeMeta::new(file_type, header.mode().map_err(invalid)?);
+    meta.uid = header.uid().map_err(invalid)? as u32;
+    meta.gid = header.gid().map_err(invalid)? as u32;
+    meta.size = entry.size();
+    meta.mtime = header.mtime().map_err(invalid)? as i64;
+    if matches!(file_type, FileType::CharDevice | FileType::BlockDevice) {
+        let major = header.device_major().map_err(invalid)?.unwrap_or(0);
+        let minor = header.device_minor().map_err(invalid)?.unwrap_or(0);
+        meta.rdev = stat::makedev(major as u64, minor as u64);
+    }
+    Ok(meta)
+}
+
+impl FileSystemTree {
+    /// Read a layer tar archive into a tree like scanning the extracted
+    /// layer. Whiteouts are found by entry name for OCI, and by char device
+    /// and PAX xattr for overlayfs. layer_path is only used to name nodes.
+    pub fn build_from_tar<R: Read>(
+        reader: R,
+        layer_path: PathBuf,
+        overlay: Overlay,
+        whiteout_spec: WhiteoutSpec,
+        option: &ScanOption,
+    ) -> Result<FileSystemTree> {
+        let mut root = TreeNode::new(
+            OsString::from("/"),
+            NodeMeta::new(FileType::Directory, 0o755),
+            overlay,
+            layer_path.clone(),
+        );
+        // replaced by the entry of the root if the archive has one
+        root.implicit = true;
+        let mut dirs = ArchiveDirs::new();
+        let mut archive = Archive::new(reader);
+        let entries = archive
+            .entries()
+            .map_err(|e| MergeTreeError::io(&layer_path, e))?;
+        for entry in entries {
+            let mut entry = entry.map_err(|e| MergeTreeError::io(&layer_path, e))?;
+            let path = PathBuf::from(OsStr::from_bytes(&entry.path_bytes()));
+            let source = layer_path.join(&path);
+            let names = entry_names(&path, &source)?;
+            if let Some(max_depth) = option.max_depth {
+                if names.len() > max_depth {
+                    return Err(MergeTreeError::TooDeep {
+                        path: source,
+                        max_depth,
+                    });
+                }
+            }
+
+            let file_type = match entry.header().entry_type() {
+                EntryType::Regular | EntryType::Continuous | EntryType::GNUSparse => {
+                    FileType::Regular
+                }
+                EntryType::Directory => FileType::Directory,
+                EntryType::Symlink => FileType::Symlink,
+                EntryType::Char => FileType::CharDevice,
+                EntryType::Block => FileType::BlockDevice,
+                EntryType::Fifo => FileType::Fifo,
+                EntryType::Link => {
+                    let target = entry.link_name_bytes().unwrap_or_default();
+                    let target = PathBuf::from(OsStr::from_bytes(&target));
+                    let (name, parents) = match names.split_last() {
+                        Some(names) => names,
+                        None => {
+                            return Err(MergeTreeError::InvalidArchive {
+                                path: source,
+                                reason: "hardlink to replace layer root".to_string(),
+                            })
+                        }
+                    };
+                    let mut node = match dirs.link_target(&entry_names(&target, &source)?) {
+                        Some(node) => node,
+                        None => {
+                            return Err(MergeTreeError::InvalidArchive {
+                                path: source,
+                                reason: format!(
+                                    "hardlink target {} is not a file before it",
+                                    target.display()
+                                ),
+                            })
+                        }
+                    };
+                    node.name = name.to_os_string();
+                    node.source = source.clone();
+                    let dir = dirs.parent_dir(parents, &layer_path, overlay)?;
+                    dirs.put(dir, node);
+                    continue;
+                }
+                // global PAX records apply to the archive, not to an entry
+                EntryType::XGlobalHeader => continue,
+                entry_type => {
+                    return Err(MergeTreeError::UnsupportedFileType {
+                        path: source,
+                        reason: format!("tar entry type {:?}", entry_type),
+                    })
+                }
+            };
+
+            let mut meta = entry_meta(&entry, file_type, &source)?;
+            let mut xattrs = XAttrs::new();
+            // uid, gid and size of PAX records are applied by tar already
+            let pax = entry
+                .pax_extensions()
+                .map_err(|e| MergeTreeError::io(&source, e))?;
+            for record in pax.into_iter().flatten() {
+                let record = record.map_err(|e| MergeTreeError::io(&source, e))?;
+                let key = match record.key() {
+                    Ok(key) => key,
+                    Err(_) => continue,
+                };
+                if let Some(xattr) = key.strip_prefix(PAX_XATTR_PREFIX) {
+                    // overlayfs opaque xattr is kept even if it is filtered out
+                    let opaque = overlay != Overlay::Lower
+                        && whiteout_spec == WhiteoutSpec::Overlayfs
+                        && xattr == OVERLAYFS_WHITEOUT_OPAQUE;
+                    if opaque || option.xattrs.keep(OsStr::new(xattr)) {
+                        xattrs.add(OsString::from(xattr), record.value_bytes().to_vec());
+                    }
+                    continue;
+                }
+                let time = match record.value().ok().and_then(parse_pax_time) {
+                    Some(time) => time,
+                    None => continue,
+                };
+                match key {
+                    "mtime" => (meta.mtime, meta.mtime_nsec) = time,
+                    "atime" => (meta.atime, meta.atime_nsec) = time,
+                    "ctime" => (meta.ctime, meta.ctime_nsec) = time,
+                    _ => {}
+                }
+            }
+
+            let name = names
+                .last()
+                .map(|name| name.to_os_string())
+                .unwrap_or_else(|| OsString::from("/"));
+            let mut node = TreeNode::new(name, meta, overlay, source);
+            node.xattrs = xattrs;
+            if file_type == FileType::Symlink {
+                let target = entry.link_name_bytes().unwrap_or_default();
+                node.link_target = Some(PathBuf::from(OsStr::from_bytes(&target)));
+            }
+            if overlay != Overlay::Lower {
+                node.build_node_overlay(whiteout_spec);
+            }
+
+            match names.split_last() {
+                Some((_, parents)) => {
+                    let dir = dirs.parent_dir(parents, &layer_path, overlay)?;
+                    dirs.put(dir, node);
+                }
+                // entry of the root itself, like "./"
+                None if node.is_directory() => {
+                    node.name = root.name.clone();
+                    node.source = root.source.clone();
+                    root = node;
+                }
+                None => {
+                    return Err(MergeTreeError::UnsupportedFileType {
+                        path: node.source,
+                        reason: "layer root is not a directory".to_string(),
+                    })
+                }
+            }
+        }
+
+        let mut scanned: ScannedDirs = dirs.dirs.into_iter().enumerate().collect();
+        if option.child_order == ChildOrder::Sorted {
+            for children in scanned.values_mut() {
+                children.sort_by(|(a, _), (b, _)| a.name.as_bytes().cmp(b.name.as_bytes()));
+            }
+        }
+        let data = Self::assemble_subtree(Tree::new(root), 0, &mut scanned);
+        let mut tree = FileSystemTree {
+            data,
+            child_order: option.child_order,
+            layers: vec![layer_path],
+        };
+        tree.prune_hardlinks();
+        Ok(tree)
+    }
+
+    /// Read a layer tar archive which may be compressed by gzip or zstd,
+    /// see build_from_tar
+    pub fn build_from_archive<R: BufRead>(
+        reader: R,
+        layer_path: PathBuf,
+        overlay: Overlay,
+        whiteout_spec: WhiteoutSpec,
+        option: &ScanOption,
+    ) -> Result<FileSystemTree> {
+        let reader = decompress(reader).map_err(|e| MergeTreeError::io(&layer_path, e))?;
+        Self::build_from_tar(reader, layer_path, overlay, whiteout_spec, option)
+    }
+
+    /// Read a layer archive like build_from_archive, and verify its digests
+    /// as it is read. The whole archive is read even after the end of tar or
+    /// a read error to hash all of it, a blob digest mismatch is reported
+    /// before the error.
+    pub fn build_from_archive_with_digests<R: Read>(
+        reader: R,
+        layer_path: PathBuf,
+        digests: &LayerDigests,
+        overlay: Overlay,
+        whiteout_spec: WhiteoutSpec,
+        option: &ScanOption,
+    ) -> Result<FileSystemTree> {
+        for digest in digests.blob.iter().chain(&digests.diff_id) {
+            check_digest_algorithm(&layer_path, digest)?;
+        }
+        let io_error = |e| MergeTreeError::io(&layer_path, e);
+        let mut blob = DigestReader::new(reader);
+        let result = decompress(BufReader::new(&mut blob))
+            .map_err(io_error)
+            .and_then(|stream| {
+                let mut stream = DigestReader::new(stream);
+                let tree = Self::build_from_tar(
+                    &mut stream,
+                    layer_path.clone(),
+                    overlay,
+                    whiteout_spec,
+                    option,
+                )?;
+                Ok((tree, stream.finish().map_err(io_error)?))
+            });
+        // blob is hashed to the end even if it fails to be read as a layer,
+        // a corrupt blob is reported by its digest instead of the tar error
+        let blob = blob.finish().map_err(io_error)?;
+        if let Some(expected) = &digests.blob {
+            verify_digest(&layer_path, expected, blob)?;
+        }
+        let (tree, diff_id) = result?;
+        if let Some(expected) = &digests.diff_id {
+            verify_digest(&layer_path, expected, diff_id)?;
+        }
+        Ok(tree)
+    }
+
+    /// Read a layer archive file, plain tar or compressed, see
+    /// build_from_archive
+    pub fn build_from_tar_file(
+        path: PathBuf,
+        overlay: Overlay,
+        whiteout_spec: WhiteoutSpec,
+        option: &ScanOption,
+    ) -> Result<FileSystemTree> {
+        let file = File::open(&path).map_err(|e| MergeTreeError::io(&path, e))?;
+        Self::build_from_archive(BufReader::new(file), path, overlay, whiteout_spec, option)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::archive::{sha256_digest, LayerDigests};
+    use crate::build::BuildTree;
+    use crate::error::MergeTreeError;
+    use crate::meta::{FileType, NodeMeta};
+    use crate::test_util::{
+        mem_node, tar_entry, tar_example_layer, tar_header, test_dir, tree_node, tree_paths,
+    };
+    use crate::tree::{ChildOrder, FileSystemTree, Overlay, ScanOption, WhiteoutSpec, XattrOption};
+    use std::ffi::OsString;
+    use std::fs;
+    use std::path::{Path, PathBuf};
+
+    #[test]
+    fn test_tar_layer_scan() {
+        use tar::EntryType;
+        let long_dir = "d".repeat(120);
+        let mut builder = tar::Builder::new(Vec::new());
+        let mut root = tar_header(EntryType::Directory);
+        root.set_mode(0o700);
+        builder.append_data(&mut root, "./", &[][..]).unwrap();
+        // ustar entry with its parent dir entry
+        let mut passwd = tar::Header::new_ustar();
+        passwd.set_entry_type(EntryType::Regular);
+        passwd.set_mode(0o600);
+        passwd.set_uid(1000);
+        passwd.set_gid(1000);
+        passwd.set_mtime(0);
+        passwd.set_size(4);
+        tar_entry(&mut builder, "./etc/", EntryType::Directory, b"");
+        builder
+            .append_data(&mut passwd, "./etc/passwd", &b"root"[..])
+            .unwrap();
+        // xattrs and time with nanoseconds in PAX records
+        builder
+            .append_pax_extensions([
+                ("SCHILY.xattr.user.foo", &b"bar"[..]),
+                ("mtime", &b"1600000000.25"[..]),
+            ])
+            .unwrap();
+        tar_entry(&mut builder, "./etc/hosts", EntryType::Regular, b"");
+        // GNU long name under dirs without entries
+        tar_entry(
+            &mut builder,
+            &format!("./usr/{}/file1", long_dir),
+            EntryType::Regular,
+            b"",
+        );
+        let mut link = tar_header(EntryType::Symlink);
+        link.set_link_name("usr/bin").unwrap();
+        builder.append_data(&mut link, "bin", &[][..]).unwrap();
+        let mut hardlink = tar_header(EntryType::Link);
+        builder
+            .append_link(&mut hardlink, "./etc/passwd-", "./etc/passwd")
+            .unwrap();
+        let mut null = tar_header(EntryType::Char);
+        null.set_device_major(1).unwrap();
+        null.set_device_minor(3).unwrap();
+        builder.append_data(&mut null, "dev/null", &[][..]).unwrap();
+        tar_entry(&mut builder, "dev/fifo", EntryType::Fifo, b"");
+        let archive = builder.into_inner().unwrap();
+
+        let tree = FileSystemTree::build_from_tar(
+            archive.as_slice(),
+            PathBuf::from("layer.tar"),
+            Overlay::Lower,
+            WhiteoutSpec::Oci,
+            &ScanOption::default(),
+        )
+        .unwrap();
+        assert_eq!(
+            tree.format_file_tree(),
+            format!(
+                "/
+├── bin -> usr/bin
+├── dev
+│  ├── fifo [fifo]
+│  ├── null [char 1:3]
+├── etc
+│  ├── hosts
+│  ├── passwd
+│  ├── passwd- => /etc/passwd
+├── usr
+│  ├── {}
+│  │  ├── file1
+",
+                long_dir
+            )
+        );
+        assert_eq!(tree.data.root().data().meta.mode, 0o700);
+        let passwd = tree_node(&tree, "/etc/passwd").unwrap();
+        assert_eq!(
+            (passwd.meta.mode, passwd.meta.uid, passwd.meta.size),
+            (0o600, 1000, 4)
+        );
+        assert_eq!(passwd.source, PathBuf::from("layer.tar/etc/passwd"));
+        let hosts = tree_node(&tree, "/etc/hosts").unwrap();
+        assert_eq!(
+            hosts.xattrs.get(&OsString::from("user.foo")),
+            Some(&b"bar".to_vec())
+        );
+        assert_eq!(
+            (hosts.meta.mtime, hosts.meta.mtime_nsec),
+            (1600000000, 250000000)
+        );
+        // parent without an entry is an implicit dir, root has an entry
+        let dev = tree_node(&tree, "/dev").unwrap();
+        assert!(dev.is_directory() && dev.implicit);
+        assert_eq!(dev.meta.mode, 0o755);
+        assert!(!tree.data.root().data().implicit);
+    }
+
+    #[test]
+    fn test_tar_layer_merge() {
+        // the same layers from dirs and from archives give the same tree
+        let dirs: Vec<PathBuf> = ["base-dir", "upper1-dir", "upper2-dir", "upper3-dir"]
+            .iter()
+            .map(|dir| Path::new("./file-example/example6").join(dir))
+            .collect();
+        let merge = |trees: Vec<FileSystemTree>| {
+            let mut trees = trees.into_iter();
+            let mut build = BuildTree::new(trees.next().unwrap());
+            for upper_tree in trees {
+                build
+                    .apply_tree_by_dfs(&upper_tree, WhiteoutSpec::Oci)
+                    .unwrap();
+            }
+            build.base_tree.format_file_tree()
+        };
+        let scan = |path: &PathBuf, overlay| {
+            FileSystemTree::build_from_file_system(path.clone(), overlay, WhiteoutSpec::Oci)
+                .unwrap()
+        };
+        let read = |path: &PathBuf, overlay| {
+            FileSystemTree::build_from_tar(
+                tar_example_layer(path).as_slice(),
+                path.with_extension("tar"),
+                overlay,
+                WhiteoutSpec::Oci,
+                &ScanOption::default(),
+            )
+            .unwrap()
+        };
+        let expected = merge(
+            dirs.iter()
+                .enumerate()
+                .map(|(i, path)| {
+                    scan(
+                        path,
+                        if i == 0 {
+                            Overlay::Lower
+                        } else {
+                            Overlay::None
+                        },
+                    )
+                })
+                .collect(),
+        );
+        let merged = merge(
+            dirs.iter()
+                .enumerate()
+                .map(|(i, path)| {
+                    read(
+                        path,
+                        if i == 0 {
+                            Overlay::Lower
+                        } else {
+                            Overlay::None
+                        },
+                    )
+                })
+                .collect(),
+        );
+        assert_eq!(merged, expected);
+
+        // archive files and dirs mixed, kept in order
+        let archive_dir = test_dir("tar-layers");
+        let upper2 = archive_dir.join("upper2.tar");
+        fs::write(&upper2, tar_example_layer(&dirs[2])).unwrap();
+        let upper_trees = FileSystemTree::build_layers(
+            vec![dirs[1].clone(), upper2.clone(), dirs[3].clone()],
+            Overlay::None,
+            WhiteoutSpec::Oci,
+            &ScanOption::default(),
+        )
+        .unwrap();
+        assert_eq!(upper_trees[1].layer_path(0), Some(upper2.as_path()));
+        let mut trees = vec![scan(&dirs[0], Overlay::Lower)];
+        trees.extend(upper_trees);
+        assert_eq!(merge(trees), expected);
+    }
+
+    #[test]
+    fn test_tar_layer_overlayfs_whiteout() {
+        let mut builder = tar::Builder::new(Vec::new());
+        let mut whiteout = tar_header(tar::EntryType::Char);
+        whiteout.set_device_major(0).unwrap();
+        whiteout.set_device_minor(0).unwrap();
+        builder.append_data(&mut whiteout, "b", &[][..]).unwrap();
+        builder
+            .append_pax_extensions([
+                ("SCHILY.xattr.trusted.overlay.opaque", &b"y"[..]),
+                ("SCHILY.xattr.user.keep", &b"1"[..]),
+            ])
+            .unwrap();
+        tar_entry(&mut builder, "a", tar::EntryType::Directory, b"");
+        tar_entry(&mut builder, "a/file3", tar::EntryType::Regular, b"");
+        let archive = builder.into_inner().unwrap();
+
+        let base_path = PathBuf::from("./file-example/example1/base-dir");
+        let base_tree = FileSystemTree::build_from_file_system(
+            base_path,
+            Overlay::Lower,
+            WhiteoutSpec::Overlayfs,
+        )
+        .unwrap();
+        // opaque xattr is kept even if it is filtered out
+        let option = ScanOption {
+            xattrs: XattrOption {
+                include: vec!["user.".to_string()],
+                ..XattrOption::default()
+            },
+            ..ScanOption::default()
+        };
+        let upper_tree = FileSystemTree::build_from_tar(
+            archive.as_slice(),
+            PathBuf::from("upper.tar"),
+            Overlay::None,
+            WhiteoutSpec::Overlayfs,
+            &option,
+        )
+        .unwrap();
+        assert!(tree_node(&upper_tree, "/b").unwrap().is_remove());
+        let mut build = BuildTree::new(base_tree);
+        build
+            .apply_tree_by_dfs(&upper_tree, WhiteoutSpec::Overlayfs)
+            .unwrap();
+        assert_eq!(tree_paths(&build.base_tree), vec!["/a", "/a/file3"]);
+        let a = tree_node(&build.base_tree, "/a").unwrap();
+        assert!(a.is_opaque());
+        assert_eq!(
+            a.xattrs.get(&OsString::from("user.keep")),
+            Some(&b"1".to_vec())
+        );
+    }
+
+    #[test]
+    fn test_tar_layer_implicit_dir_merge() {
+        let dir = NodeMeta::new(FileType::Directory, 0o755);
+        let keep = OsString::from("user.keep");
+        let mut root = mem_node(
+            "/",
+            NodeMeta {
+                mode: 0o711,
+                ..dir.clone()
+            },
+            Overlay::Lower,
+        );
+        root.xattrs.add(keep.clone(), b"root".to_vec());
+        let mut base = FileSystemTree::new(root, PathBuf::from("base"), ChildOrder::Sorted);
+        let mut usr = mem_node(
+            "usr",
+            NodeMeta {
+                mode: 0o700,
+                mtime: 42,
+                ..dir.clone()
+            },
+            Overlay::Lower,
+        );
+        usr.xattrs.add(keep.clone(), b"usr".to_vec());
+        assert!(base.insert(Path::new("/usr"), usr));
+        assert!(base.insert(
+            Path::new("/etc"),
+            mem_node("etc", dir.clone(), Overlay::Lower)
+        ));
+
+        // no "./" entry and no entry of usr, the entry of etc comes after
+        // its child
+        let mut builder = tar::Builder::new(Vec::new());
+        tar_entry(&mut builder, "usr/new", tar::EntryType::Regular, b"");
+        tar_entry(&mut builder, "etc/hosts", tar::EntryType::Regular, b"");
+        let mut etc = tar_header(tar::EntryType::Directory);
+        etc.set_mode(0o750);
+        builder.append_data(&mut etc, "etc/", &[][..]).unwrap();
+        let archive = builder.into_inner().unwrap();
+        let upper_tree = FileSystemTree::build_from_tar(
+            archive.as_slice(),
+            PathBuf::from("up.tar"),
+            Overlay::None,
+            WhiteoutSpec::Oci,
+            &ScanOption::default(),
+        )
+        .unwrap();
+        assert!(upper_tree.data.root().data().implicit);
+        assert!(tree_node(&upper_tree, "/usr").unwrap().implicit);
+        assert!(!tree_node(&upper_tree, "/etc").unwrap().implicit);
+
+        let mut build = BuildTree::new(base);
+        build
+            .apply_tree_by_dfs(&upper_tree, WhiteoutSpec::Oci)
+            .unwrap();
+        assert_eq!(
+            tree_paths(&build.base_tree),
+            vec!["/etc", "/etc/hosts", "/usr", "/usr/new"]
+        );
+        // dirs without entries keep meta, xattrs and layer of base
+        let root = build.base_tree.data.root().data();
+        assert_eq!(root.meta.mode, 0o711);
+        assert_eq!(root.xattrs.get(&keep), Some(&b"root".to_vec()));
+        assert_eq!((root.layer, root.shadowed.clone()), (0, vec![]));
+        let usr = tree_node(&build.base_tree, "/usr").unwrap();
+        assert_eq!((usr.meta.mode, usr.meta.mtime), (0o700, 42));
+        assert_eq!(usr.xattrs.get(&keep), Some(&b"usr".to_vec()));
+        assert_eq!((usr.layer, usr.shadowed.clone()), (0, vec![]));
+        assert_eq!(tree_node(&build.base_tree, "/usr/new").unwrap().layer, 1);
+        // dir with an entry comes from upper
+        let etc = tree_node(&build.base_tree, "/etc").unwrap();
+        assert_eq!(etc.meta.mode, 0o750);
+        assert_eq!((etc.layer, etc.shadowed.clone()), (1, vec![0]));
+    }
+
+    #[test]
+    fn test_tar_layer_error() {
+        let read = |archive: Vec<u8>| {
+            FileSystemTree::build_from_tar(
+                archive.as_slice(),
+                PathBuf::from("layer.tar"),
+                Overlay::None,
+                WhiteoutSpec::Oci,
+                &ScanOption::default(),
+            )
+            .err()
+            .unwrap()
+        };
+
+        // tar builder refuses "..", so write the name into the header
+        let mut builder = tar::Builder::new(Vec::new());
+        let mut header = tar_header(tar::EntryType::Regular);
+        let name = b"a/../../etc/passwd";
+        header.as_gnu_mut().unwrap().name[..name.len()].copy_from_slice(name);
+        header.set_cksum();
+        builder.append(&header, &[][..]).unwrap();
+        let err = read(builder.into_inner().unwrap());
+        assert!(matches!(err, MergeTreeError::InvalidArchive { .. }));
+        assert_eq!(err.exit_code(), 8);
+        assert!(err.to_string().contains("layer.tar/a/../../etc/passwd"));
+
+        // hardlink to an entry later in the archive
+        let mut builder = tar::Builder::new(Vec::new());
+        let mut link = tar_header(tar::EntryType::Link);
+        builder.append_link(&mut link, "file2", "file1").unwrap();
+        tar_entry(&mut builder, "file1", tar::EntryType::Regular, b"");
+        let err = read(builder.into_inner().unwrap());
+        assert!(matches!(err, MergeTreeError::InvalidArchive { .. }));
+
+        // file as parent of an entry
+        let mut builder = tar::Builder::new(Vec::new());
+        tar_entry(&mut builder, "file1", tar::EntryType::Regular, b"");
+        tar_entry(&mut builder, "file1/file2", tar::EntryType::Regular, b"");
+        let err = read(builder.into_inner().unwrap());
+        assert!(err.to_string().contains("layer.tar/file1"));
+
+        let option = ScanOption {
+            max_depth: Some(1),
+            ..ScanOption::default()
+        };
+        let mut builder = tar::Builder::new(Vec::new());
+        tar_entry(&mut builder, "a/file1", tar::EntryType::Regular, b"");
+        let err = FileSystemTree::build_from_tar(
+            builder.into_inner().unwrap().as_slice(),
+            PathBuf::from("layer.tar"),
+            Overlay::Lower,
+            WhiteoutSpec::Oci,
+            &option,
+        )
+        .err()
+        .unwrap();
+        assert!(matches!(err, MergeTreeError::TooDeep { .. }));
+    }
+
+    #[test]
+    fn test_compressed_tar_layer() {
+        use std::io::Write;
+        let archive = tar_example_layer(Path::new("./file-example/example6/base-dir"));
+        let mut gzip = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        gzip.write_all(&archive).unwrap();
+        let gzip = gzip.finish().unwrap();
+        let zstd = zstd::encode_all(archive.as_slice(), 0).unwrap();
+        let read = |archive: &[u8]| {
+            FileSystemTree::build_from_archive(
+                archive,
+                PathBuf::from("base.tar"),
+                Overlay::Lower,
+                WhiteoutSpec::Oci,
+                &ScanOption::default(),
+            )
+        };
+
+        let expected = read(&archive).unwrap().format_file_tree();
+        assert_eq!(read(&gzip).unwrap().format_file_tree(), expected);
+        assert_eq!(read(&zstd).unwrap().format_file_tree(), expected);
+
+        // compression of a layer file is found by content, not by extension
+        let path = test_dir("compressed-layer").join("base.tar");
+        fs::write(&path, &zstd).unwrap();
+        let tree = FileSystemTree::build_layer(
+            path,
+            Overlay::Lower,
+            WhiteoutSpec::Oci,
+            &ScanOption::default(),
+        )
+        .unwrap();
+        assert_eq!(tree.format_file_tree(), expected);
+
+        let err = read(&gzip[..gzip.len() / 2]).err().unwrap();
+        assert!(matches!(err, MergeTreeError::Io { .. }));
+    }
+
+    #[test]
+    fn test_layer_digests() {
+        use std::io::Write;
+        let tar = tar_example_layer(Path::new("./file-example/example6/base-dir"));
+        let mut gzip = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        gzip.write_all(&tar).unwrap();
+        let gzip = gzip.finish().unwrap();
+        let read = |blob: &[u8], digests: LayerDigests| {
+            FileSystemTree::build_from_archive_with_digests(
+                blob,
+                PathBuf::from("layer.tar.gz"),
+                &digests,
+                Overlay::Lower,
+                WhiteoutSpec::Oci,
+                &ScanOption::default(),
+            )
+        };
+
+        // blob is the compressed archive, diff_id is the tar in it
+        let tree = read(
+            &gzip,
+            LayerDigests {
+                blob: Some(sha256_digest(&gzip)),
+                diff_id: Some(sha256_digest(&tar)),
+            },
+        )
+        .unwrap();
+        assert!(tree_node(&tree, "/a/file1").is_some());
+        let err = read(
+            &gzip,
+            LayerDigests {
+                blob: Some(sha256_digest(&tar)),
+                diff_id: None,
+            },
+        )
+        .err()
+        .unwrap();
+        assert!(matches!(err, MergeTreeError::DigestMismatch { .. }));
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "digest mismatch of layer.tar.gz: expected {}, got {}",
+                sha256_digest(&tar),
+                sha256_digest(&gzip)
+            )
+        );
+        let err = read(
+            &gzip,
+            LayerDigests {
+                blob: None,
+                diff_id: Some(sha256_digest(&gzip)),
+            },
+        )
+        .err()
+        .unwrap();
+        assert!(matches!(err, MergeTreeError::DigestMismatch { .. }));
+        let err = read(
+            &gzip,
+            LayerDigests {
+                blob: Some(format!("sha512:{}", "0".repeat(128))),
+                diff_id: None,
+            },
+        )
+        .err()
+        .unwrap();
+        assert!(err.to_string().contains("unsupported digest sha512"));
+
+        // a corrupt blob fails to be read, and is reported by its digest
+        let mut flipped = gzip.clone();
+        let middle = flipped.len() / 2;
+        flipped[middle] ^= 0x10;
+        let truncated = &gzip[..gzip.len() - 16];
+        for blob in [&flipped[..], truncated] {
+            let digests = LayerDigests {
+                blob: Some(sha256_digest(&gzip)),
+                diff_id: None,
+            };
+            let err = read(blob, digests).err().unwrap();
+            assert!(matches!(err, MergeTreeError::DigestMismatch { .. }));
+            assert!(err.to_string().contains(&sha256_digest(blob)));
+        }
+        // without blob digest the read error is kept
+        let digests = LayerDigests {
+            blob: None,
+            diff_id: Some(sha256_digest(&tar)),
+        };
+        let err = read(truncated, digests).err().unwrap();
+        assert!(!matches!(err, MergeTreeError::DigestMismatch { .. }));
+    }
+}
@@ -1,419 +1,1577 @@
-use crate::tree::{FileSystemTree, Overlay, TreeNode, WhiteoutSpec, WhiteoutType};
+use crate::error::{MergeTreeError, Result};
+use crate::tree::{
+    drop_tree, ChildOrder, DisplayOption, FileSystemTree, Overlay, TreeNode, WhiteoutSpec,
+    WhiteoutType,
+};
+use std::collections::HashMap;
+use std::ffi::{OsStr, OsString};
+use std::os::unix::ffi::OsStrExt;
+use std::path::PathBuf;
 use trees::{Node, Tree};
 
 pub const OCI_WHITEOUT_PREFIX: &str = ".wh.";
 pub const OCI_WHITEOUT_OPAQUE: &str = ".wh..wh..opq";
 pub const OVERLAYFS_WHITEOUT_OPAQUE: &str = "trusted.overlay.opaque";
+// xattrs private to overlayfs, like opaque, redirect and origin
+pub const OVERLAYFS_XATTR_PREFIX: &str = "trusted.overlay.";
+
+/// How to handle a whiteout whose target doesn't exist in lower layers
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OrphanWhiteout {
+    /// Collect it into diagnostics and go on
+    Lenient,
+    /// Fail the merge
+    Strict,
+}
+
+// state of merging one upper tree
+struct MergeContext {
+    whiteout_spec: WhiteoutSpec,
+    // child order of base tree to keep when adding entries
+    child_order: ChildOrder,
+    // whiteouts which remove nothing in lower layers
+    orphans: Vec<PathBuf>,
+    // index in base tree layers of the first layer of upper tree
+    layer_offset: usize,
+}
+
+// lower children of a dir up to this many are searched by name one by one,
+// an index is only worth building for larger dirs
+const LOWER_SEARCH_MAX: usize = 16;
+
+// a base dir whose children are being merged, detached from its parent until
+// all of them are merged
+struct MergeFrame<'a> {
+    base: Tree<TreeNode>,
+    upper: &'a Node<TreeNode>,
+    path: PathBuf,
+    // upper children not merged yet
+    children: std::vec::IntoIter<&'a Node<TreeNode>>,
+    // lower children taken out of base dir in their order, None once removed
+    // or while being merged by a child frame
+    lower: Vec<Option<Tree<TreeNode>>>,
+    // position in lower of every lower child still there by name, built on
+    // the first lookup in a dir too large to search
+    index: Option<HashMap<OsString, usize>>,
+    // upper children added to base dir, put into it after lower ones
+    additions: Vec<Tree<TreeNode>>,
+    // position in lower of parent to put it back, None to add it to parent
+    position: Option<usize>,
+}
+
+impl<'a> MergeFrame<'a> {
+    fn new(
+        mut base: Tree<TreeNode>,
+        upper: &'a Node<TreeNode>,
+        path: PathBuf,
+        position: Option<usize>,
+    ) -> Self {
+        let mut lower = Vec::new();
+        while let Some(child) = base.pop_front() {
+            lower.push(Some(child));
+        }
+        MergeFrame {
+            base,
+            upper,
+            path,
+            children: upper.iter().collect::<Vec<_>>().into_iter(),
+            lower,
+            index: None,
+            additions: Vec::new(),
+            position,
+        }
+    }
+
+    // take the lower child of the name out of the frame
+    fn take_lower(&mut self, name: &OsStr) -> Option<(usize, Tree<TreeNode>)> {
+        let position = if self.lower.len() <= LOWER_SEARCH_MAX {
+            self.lower.iter().position(|child| {
+                child
+                    .as_ref()
+                    .is_some_and(|child| child.root().data().name == name)
+            })?
+        } else {
+            let lower = &self.lower;
+            let index = self.index.get_or_insert_with(|| {
+                let names = lower.iter().enumerate().filter_map(|(position, child)| {
+                    let child = child.as_ref()?;
+                    Some((child.root().data().name.clone(), position))
+                });
+                names.collect()
+            });
+            index.remove(name)?
+        };
+        self.lower[position].take().map(|child| (position, child))
+    }
+
+    // put children back to base dir, lower ones left in their order and
+    // additions placed by child order. Additions are sorted once and merged
+    // with lower children which are sorted already, instead of being
+    // inserted one by one.
+    fn into_tree(self, child_order: ChildOrder) -> Tree<TreeNode> {
+        let mut base = self.base;
+        let mut additions = self.additions;
+        if child_order == ChildOrder::Sorted {
+            additions.sort_by(|a, b| {
+                let a = a.root().data().name.as_bytes();
+                a.cmp(b.root().data().name.as_bytes())
+            });
+        }
+        let mut additions = additions.into_iter().peekable();
+        for child in self.lower.into_iter().flatten() {
+            if child_order == ChildOrder::Sorted {
+                let name = child.root().data().name.as_bytes();
+                while let Some(addition) =
+                    additions.next_if(|addition| addition.root().data().name.as_bytes() < name)
+                {
+                    base.push_back(addition);
+                }
+            }
+            base.push_back(child);
+        }
+        for addition in additions {
+            base.push_back(addition);
+        }
+        base
+    }
+}
 
 pub struct BuildTree {
     pub base_tree: FileSystemTree,
+    pub orphan_whiteout: OrphanWhiteout,
+    /// Problems found while merging in lenient mode, like orphan whiteouts
+    pub diagnostics: Vec<String>,
 }
 
 impl BuildTree {
     pub fn new(base_tree: FileSystemTree) -> Self {
-        BuildTree { base_tree }
+        BuildTree {
+            base_tree,
+            orphan_whiteout: OrphanWhiteout::Lenient,
+            diagnostics: Vec::new(),
+        }
     }
 
     // apply upper tree to base tree
+    //
+    // Base and upper trees are travelled side by side from the root, so every
+    // upper entry is resolved by its full path and can only land under the
+    // base directory with the same path.
+    // On error the base tree may be left partially merged.
     pub fn apply_tree_by_dfs(
         &mut self,
-        node: &Node<TreeNode>,
-        level: u32,
+        upper_tree: &FileSystemTree,
         whiteout_spec: WhiteoutSpec,
-    ) {
-        // travel base tree by dfs and then to merge
-        Self::merge_tree_dfs(
-            self.base_tree.data.root_mut().get_mut(),
-            0,
-            node,
-            level,
+    ) -> Result<()> {
+        let mut ctx = MergeContext {
             whiteout_spec,
-        );
+            child_order: self.base_tree.child_order,
+            orphans: Vec::new(),
+            layer_offset: self.base_tree.layers.len(),
+        };
+        self.base_tree
+            .layers
+            .extend(upper_tree.layers.iter().cloned());
+        // case0, root dir is merged like any other dir, its metadata is taken
+        // from upper root and an opaque upper root hides the whole base
+        let base_root = self.base_tree.data.root_mut().get_mut();
+        Self::merge_dir(base_root, upper_tree.data.root(), &ctx);
+        Self::merge_tree_dfs(&mut self.base_tree.data, upper_tree.data.root(), &mut ctx)?;
+        // names of a hardlink group may be removed by whiteout or replaced by
+        // upper entry, a file left with one name is not a hardlink any more
+        self.base_tree.prune_hardlinks();
 
-        if !node.has_no_child() {
-            for n in node.iter() {
-                self.apply_tree_by_dfs(n, level + 1, whiteout_spec)
-            }
+        if ctx.orphans.is_empty() {
+            return Ok(());
+        }
+        if self.orphan_whiteout == OrphanWhiteout::Strict {
+            return Err(MergeTreeError::OrphanWhiteout { paths: ctx.orphans });
         }
+        let orphans = ctx
+            .orphans
+            .into_iter()
+            .map(|path| MergeTreeError::OrphanWhiteout { paths: vec![path] }.to_string());
+        self.diagnostics.extend(orphans);
+        Ok(())
     }
 
-    // travel base tree to
+    // merge children of upper root into base root, and so on for every dir
+    // below them. Dirs are travelled with a stack of frames instead of
+    // recursion, so a very deep tree can't overflow the stack.
     fn merge_tree_dfs(
-        base_node: &mut Node<TreeNode>,
-        level: u32,
-        upper_node: &Node<TreeNode>,
-        upper_level: u32,
-        whiteout_spec: WhiteoutSpec,
-    ) {
-        // case0, whether to handle root dir? we should think
-        if upper_level == 0 {
-            return;
+        base_tree: &mut Tree<TreeNode>,
+        upper_root: &Node<TreeNode>,
+        ctx: &mut MergeContext,
+    ) -> Result<()> {
+        // base root is moved into the first frame and put back at the end
+        let placeholder = Tree::new(base_tree.root().data().clone());
+        let base_root = std::mem::replace(base_tree, placeholder);
+        let mut stack = vec![MergeFrame::new(
+            base_root,
+            upper_root,
+            PathBuf::from("/"),
+            None,
+        )];
+        let result = Self::merge_frames(&mut stack, ctx);
+        // put merged dirs back to their parents, and on error the dirs still
+        // being merged too, so that no part of base tree is lost
+        while stack.len() > 1 {
+            let frame = stack.pop().unwrap();
+            Self::attach_frame(stack.last_mut().unwrap(), frame, ctx.child_order);
         }
+        *base_tree = stack.pop().unwrap().into_tree(ctx.child_order);
+        result
+    }
 
-        // case1, don't search if base path recursive depth out of upper level
-        if level > upper_level {
-            return;
+    fn merge_frames<'a>(stack: &mut Vec<MergeFrame<'a>>, ctx: &mut MergeContext) -> Result<()> {
+        let frame = stack.last_mut().unwrap();
+        Self::apply_whiteouts(frame, ctx)?;
+        loop {
+            let frame = stack.last_mut().unwrap();
+            match frame.children.next() {
+                Some(upper_child) => {
+                    if let Some(child_frame) = Self::merge_child(frame, upper_child, ctx) {
+                        stack.push(child_frame);
+                        Self::apply_whiteouts(stack.last_mut().unwrap(), ctx)?;
+                    }
+                }
+                None => {
+                    // all children merged, the dir goes back to its parent
+                    if stack.len() == 1 {
+                        return Ok(());
+                    }
+                    let frame = stack.pop().unwrap();
+                    Self::attach_frame(stack.last_mut().unwrap(), frame, ctx.child_order);
+                }
+            }
         }
+    }
 
-        let mut parent_name: String = String::from("");
-        let node_name: String = base_node.data().name.to_string();
-        match base_node.parent() {
-            None => {}
-            Some(parent) => parent_name = parent.data().name.to_string(),
-        }
-
-        let mut upper_parent_name: String = String::from("");
-        let upper_node_name = upper_node.data().name.to_string();
-        match upper_node.parent() {
-            None => {}
-            Some(upper_parent) => upper_parent_name = upper_parent.data().name.to_string(),
-        }
-
-        //case2, whiteout handle
-        if upper_node.data().is_whiteout() {
-            let found;
-            let whiteout_type = upper_node.data().whiteout_type(&whiteout_spec).unwrap();
-            found = Self::handle_whiteout(
-                base_node,
-                level,
-                node_name.clone(),
-                upper_node,
-                upper_node_name.clone(),
-                upper_level,
-                upper_parent_name.clone(),
-                whiteout_type,
-            );
-            if found {
-                println!(
-                    "already handle whiteout for upper node {}, return",
-                    upper_node_name
-                );
-                return;
+    //case2, whiteout handle. Whiteouts only apply to lower layers, never to
+    // siblings of the same layer, so all of them are handled before merging
+    // any entry of this dir, whatever order the entries are read in
+    fn apply_whiteouts(frame: &mut MergeFrame, ctx: &mut MergeContext) -> Result<()> {
+        let whiteout_spec = ctx.whiteout_spec;
+        // lower children of an opaque dir are hidden before its whiteouts are
+        // applied, a whiteout next to the opaque marker is redundant and not
+        // an orphan
+        let opaque = Self::is_opaque_dir(frame.upper, whiteout_spec);
+        let upper = frame.upper;
+        for upper_child in upper.iter() {
+            if !upper_child.data().is_whiteout() {
+                continue;
+            }
+            let upper_node_name = upper_child.data().name.as_os_str();
+            // upper tree is scanned with another whiteout spec
+            let whiteout_type = upper_child
+                .data()
+                .whiteout_type(&whiteout_spec)
+                .ok_or_else(|| MergeTreeError::InvalidWhiteout {
+                    path: frame.path.join(upper_node_name),
+                    reason: "not a whiteout of the given spec".to_string(),
+                })?;
+            if whiteout_type == WhiteoutType::OciRemoval
+                && upper_node_name.len() == OCI_WHITEOUT_PREFIX.len()
+            {
+                return Err(MergeTreeError::InvalidWhiteout {
+                    path: frame.path.join(upper_node_name),
+                    reason: "no file name after whiteout prefix".to_string(),
+                });
+            }
+            // opaque is handled when merging the dir it belongs to
+            if whiteout_type == WhiteoutType::OciOpaque
+                || whiteout_type == WhiteoutType::OverlayFsOpaque
+            {
+                continue;
+            }
+            // whiteout is consumed whether its target is found or not, it
+            // must never leak into merged tree
+            if !Self::handle_whiteout(frame, upper_node_name, whiteout_type) && !opaque {
+                ctx.orphans.push(frame.path.join(upper_node_name));
             }
         }
+        Ok(())
+    }
 
-        // case3, lower level is the parent of upper node
-        if level + 1 == upper_level && node_name == upper_parent_name {
-            // addition, node is upper
-            log::trace!(
-                "node_parent_name{}, node_name {}, upper_parent_name {}, upper_node_name {}",
-                parent_name,
-                node_name,
-                upper_parent_name,
-                upper_node_name
-            );
+    // merge an upper child into base dir of the frame, return the frame of
+    // the child if it is a dir whose children are to be merged
+    fn merge_child<'a>(
+        frame: &mut MergeFrame<'a>,
+        upper_child: &'a Node<TreeNode>,
+        ctx: &MergeContext,
+    ) -> Option<MergeFrame<'a>> {
+        let whiteout_spec = ctx.whiteout_spec;
+        let upper_node_name = upper_child.data().name.as_os_str();
 
-            // travel child of base node to find upper node
-            let mut found = false;
-            for mut node_child in base_node.iter_mut() {
-                if node_child.data().name == upper_node_name {
-                    // case3.1 upper is gen file，handle modification
-                    found = true;
-                    if !upper_node.data().is_directory() {
-                        node_child.detach();
-                        found = false;
-                        break;
-                    }
-                }
-            }
+        // whiteouts are handled above and OCI opaque marker is dropped,
+        // overlayfs opaque dir is merged like a normal dir
+        if upper_child.data().is_whiteout()
+            && upper_child.data().whiteout_type(&whiteout_spec)
+                != Some(WhiteoutType::OverlayFsOpaque)
+        {
+            return None;
+        }
 
-            // case3.2 handle addition
-            if !found {
-                let new_tree = Tree::new(TreeNode::new(
-                    upper_node.data().name.to_string(),
-                    upper_node.data().meta.clone(),
-                    Overlay::UpperAddition,
+        // case3, find lower child of base node with the name of upper node
+        let child_path = frame.path.join(upper_node_name);
+        let mut shadowed = Vec::new();
+        if let Some((position, mut base_child)) = frame.take_lower(upper_node_name) {
+            if upper_child.data().is_directory() && base_child.root().data().is_directory() {
+                // case3.1 both are dir, merge dir itself and then its
+                // children, the dir is detached until they are merged
+                Self::merge_dir(base_child.root_mut().get_mut(), upper_child, ctx);
+                return Some(MergeFrame::new(
+                    base_child,
+                    upper_child,
+                    child_path,
+                    Some(position),
                 ));
-                base_node.push_back(new_tree)
             }
+            // case3.2 file type is changed or upper is not dir, upper
+            // replaces the whole lower entry, e.g. dir replaces file,
+            // file or symlink replaces dir subtree
+            shadowed = base_child.root().data().shadowed.clone();
+            shadowed.push(base_child.root().data().layer);
+            drop_tree(base_child);
+        }
+
+        // case3.3 handle addition, keep the full upper node like xattrs and
+        // source path, and its descendants are merged into it by its frame
+        let mut new_node = upper_child.data().clone();
+        if whiteout_spec == WhiteoutSpec::Overlayfs {
+            new_node.xattrs.remove_prefix(OVERLAYFS_XATTR_PREFIX);
+        }
+        new_node.overlay = if Self::is_opaque_dir(upper_child, whiteout_spec) {
+            Overlay::UpperOpaque
+        } else {
+            Overlay::UpperAddition
+        };
+        new_node.layer += ctx.layer_offset;
+        if let Some(key) = new_node.hardlink.as_mut() {
+            key.layer += ctx.layer_offset;
+        }
+        shadowed.extend(new_node.shadowed.iter().map(|l| l + ctx.layer_offset));
+        new_node.shadowed = shadowed;
+        let new_tree = Tree::new(new_node);
+        if upper_child.data().is_directory() {
+            return Some(MergeFrame::new(new_tree, upper_child, child_path, None));
         }
+        frame.additions.push(new_tree);
+        None
+    }
+
+    // put merged dir back to its parent, at the position it was detached from
+    fn attach_frame(parent: &mut MergeFrame, frame: MergeFrame, child_order: ChildOrder) {
+        let position = frame.position;
+        let base = frame.into_tree(child_order);
+        match position {
+            Some(position) => parent.lower[position] = Some(base),
+            None => parent.additions.push(base),
+        }
+    }
 
-        // current node is dir, should recurse
-        if !base_node.has_no_child() {
-            for n in base_node.iter_mut() {
-                Self::merge_tree_dfs(
-                    n.get_mut(),
-                    level + 1,
-                    upper_node,
-                    upper_level,
-                    whiteout_spec,
-                )
+    // merge upper dir into base dir of the same path, upper dir metadata like
+    // mode, owner, timestamps and xattrs overrides lower one, and lower
+    // children are hidden if upper dir is opaque
+    fn merge_dir(base_node: &mut Node<TreeNode>, upper_node: &Node<TreeNode>, ctx: &MergeContext) {
+        let upper_data = upper_node.data();
+        let node_data = base_node.data_mut();
+        // upper layer has no entry of the dir, only its children are merged
+        if upper_data.implicit {
+            if Self::is_opaque_dir(upper_node, ctx.whiteout_spec) {
+                Self::handle_opaque(base_node);
             }
+            return;
+        }
+        node_data.meta = upper_data.meta.clone();
+        // xattrs are not merged key by key, the upper file has the whole set
+        // of them like overlayfs copy-up and OCI layer entry, except those
+        // private to an overlayfs upper dir which are never shown in merged
+        // dir. In an OCI layer they are ordinary xattrs of the file.
+        node_data.xattrs = upper_data.xattrs.clone();
+        if ctx.whiteout_spec == WhiteoutSpec::Overlayfs {
+            node_data.xattrs.remove_prefix(OVERLAYFS_XATTR_PREFIX);
+        }
+        node_data.mount_point = upper_data.mount_point;
+        node_data.source = upper_data.source.clone();
+        // dir now comes from upper layer and shadows lower one, unless no
+        // lower layer had an entry of it
+        if !node_data.implicit {
+            node_data.shadowed.push(node_data.layer);
+        }
+        node_data.implicit = false;
+        node_data
+            .shadowed
+            .extend(upper_data.shadowed.iter().map(|l| l + ctx.layer_offset));
+        node_data.layer = upper_data.layer + ctx.layer_offset;
+        if Self::is_opaque_dir(upper_node, ctx.whiteout_spec) {
+            Self::handle_opaque(base_node);
+        }
+    }
+
+    // whether upper dir is opaque in its layer, by OCI opaque marker inside
+    // it or by overlayfs opaque xattr on it. A mount point is opaque too, the
+    // mounted file system hides what is under it.
+    fn is_opaque_dir(upper_node: &Node<TreeNode>, whiteout_spec: WhiteoutSpec) -> bool {
+        if !upper_node.data().is_directory() {
+            return false;
+        }
+        if upper_node.data().mount_point {
+            return true;
+        }
+        match whiteout_spec {
+            WhiteoutSpec::Oci => upper_node.iter().any(|child| {
+                child.data().whiteout_type(&whiteout_spec) == Some(WhiteoutType::OciOpaque)
+            }),
+            WhiteoutSpec::Overlayfs => {
+                upper_node.data().whiteout_type(&whiteout_spec)
+                    == Some(WhiteoutType::OverlayFsOpaque)
+            }
+        }
+    }
+
+    // opaque hides every lower child of the dir but keeps the dir itself, it
+    // must be handled before merging children of the same layer into the dir
+    fn handle_opaque(base_node: &mut Node<TreeNode>) {
+        while let Some(child) = base_node.pop_front() {
+            drop_tree(child);
         }
+        base_node.data_mut().overlay = Overlay::UpperOpaque;
     }
 
+    // handle whiteout of upper node against lower children of base dir
     fn handle_whiteout(
-        base_node: &mut Node<TreeNode>,
-        base_level: u32,
-        base_node_name: String,
-        upper_node: &Node<TreeNode>,
-        upper_node_name: String,
-        upper_level: u32,
-        upper_parent_name: String,
+        frame: &mut MergeFrame,
+        upper_node_name: &OsStr,
         whiteout_type: WhiteoutType,
     ) -> bool {
-        if base_level + 1 == upper_level && base_node_name == upper_parent_name {
-            for mut child in base_node.iter_mut() {
-                //Case2.1 OCI remove
-                if whiteout_type == WhiteoutType::OciRemoval
-                    && upper_node_name.as_str()[OCI_WHITEOUT_PREFIX.len()..] == child.data().name
-                {
-                    child.detach();
-                    return true;
-                }
-                //Case2.2 Overlayfs remove
-                if whiteout_type == WhiteoutType::OverlayFsRemoval
-                    && upper_node_name == child.data().name
-                {
-                    child.detach();
-                    return true;
-                }
-                //Case2.3 Overlayfs opaque
-                if whiteout_type == WhiteoutType::OverlayFsOpaque
-                    && upper_node_name == child.data().name
-                {
-                    child.detach();
-                    return true;
-                }
+        let name = match whiteout_type {
+            //Case2.1 OCI remove
+            WhiteoutType::OciRemoval => {
+                OsStr::from_bytes(&upper_node_name.as_bytes()[OCI_WHITEOUT_PREFIX.len()..])
             }
-            //Case2.4 OCI opaque
-            if whiteout_type == WhiteoutType::OciOpaque {
-                base_node.detach();
-                return true;
+            //Case2.2 Overlayfs remove
+            WhiteoutType::OverlayFsRemoval => upper_node_name,
+            _ => return false,
+        };
+        match frame.take_lower(name) {
+            Some((_, child)) => {
+                drop_tree(child);
+                true
             }
+            None => false,
         }
-        // current node is dir, should recurse
-        if !base_node.has_no_child() {
-            for n in base_node.iter_mut() {
-                if Self::handle_whiteout(
-                    n.get_mut(),
-                    base_level + 1,
-                    base_node_name.clone(),
-                    upper_node,
-                    upper_node_name.clone(),
-                    upper_level,
-                    upper_parent_name.clone(),
-                    whiteout_type.clone(),
-                ) {
-                    return true;
-                }
-            }
-        }
-        false
     }
 
+    #[allow(dead_code)]
     pub fn display_base_tree(&self) {
         self.base_tree.display_file_tree()
     }
+
+    pub fn display_base_tree_with_option(&self, option: &DisplayOption) {
+        self.base_tree.display_file_tree_with_option(option)
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::build::BuildTree;
-    use crate::tree::{FileSystemTree, Overlay, WhiteoutSpec};
-    use std::path::PathBuf;
+    use crate::build::{BuildTree, OrphanWhiteout, OVERLAYFS_WHITEOUT_OPAQUE};
+    use crate::error::MergeTreeError;
+    use crate::meta::{FileType, NodeMeta};
+    use crate::test_util::{gen_hardlink_dir, mem_node, test_dir, tree_node, tree_paths};
+    use crate::tree::XattrOption;
+    use crate::tree::{
+        ChildOrder, DisplayOption, FileSystemTree, Overlay, ScanOption, TreeNode, WhiteoutSpec,
+    };
+    use nix::sys::stat::{self, Mode, SFlag};
+    use std::ffi::{OsStr, OsString};
+    use std::fs;
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::fs::{MetadataExt, PermissionsExt};
+    use std::path::{Path, PathBuf};
+    use trees::{Node, Tree};
 
-    #[test]
-    fn test_common_merge() {
-        let base_path = PathBuf::from("./file-example/example1/base-dir");
-        let base_tree =
-            FileSystemTree::build_from_file_system(base_path, Overlay::Lower, WhiteoutSpec::Oci)
-                .unwrap();
+    // merge upper-dir of example into its base-dir with oci whiteout
+    fn merge_example(example: &str) -> BuildTree {
+        let example = Path::new("./file-example").join(example);
+        merge_dirs(
+            &example.join("base-dir"),
+            &[example.join("upper-dir")],
+            WhiteoutSpec::Oci,
+            |_| {},
+        )
+        .unwrap()
+    }
+
+    // merge upper dirs into base dir from bottom to top, configure sets up
+    // the build before merging
+    fn merge_dirs(
+        base_path: &Path,
+        upper_paths: &[PathBuf],
+        whiteout_spec: WhiteoutSpec,
+        configure: impl FnOnce(&mut BuildTree),
+    ) -> Result<BuildTree, MergeTreeError> {
+        let option = ScanOption::default();
+        merge_dirs_with_option(base_path, upper_paths, whiteout_spec, &option, configure)
+    }
+
+    // merge_dirs with every layer scanned by option
+    fn merge_dirs_with_option(
+        base_path: &Path,
+        upper_paths: &[PathBuf],
+        whiteout_spec: WhiteoutSpec,
+        option: &ScanOption,
+        configure: impl FnOnce(&mut BuildTree),
+    ) -> Result<BuildTree, MergeTreeError> {
+        let base_tree = FileSystemTree::build_from_file_system_with_option(
+            base_path.to_path_buf(),
+            Overlay::Lower,
+            whiteout_spec,
+            option,
+        )
+        .unwrap();
         println!("show base tree");
         base_tree.display_file_tree();
 
-        let upper_path = PathBuf::from("./file-example/example1/upper-dir");
-        let upper_tree =
-            FileSystemTree::build_from_file_system(upper_path, Overlay::None, WhiteoutSpec::Oci)
-                .unwrap();
-        println!("show upper tree");
-        upper_tree.display_file_tree();
-
         let mut build = BuildTree::new(base_tree);
-        build.apply_tree_by_dfs(upper_tree.data.root(), 0, WhiteoutSpec::Oci);
+        configure(&mut build);
+        for upper_path in upper_paths {
+            let upper_tree = FileSystemTree::build_from_file_system_with_option(
+                upper_path.clone(),
+                Overlay::None,
+                whiteout_spec,
+                option,
+            )
+            .unwrap();
+            println!("show upper tree {}", upper_path.display());
+            upper_tree.display_file_tree();
+            build.apply_tree_by_dfs(&upper_tree, whiteout_spec)?;
+        }
 
         println!("show merge tree");
-        build.display_base_tree()
+        build.display_base_tree();
+        Ok(build)
+    }
+
+    // Overlayfs whiteouts are char devices and opaque dirs need trusted xattrs,
+    // neither can be kept in git, so generate the upper dir before testing.
+    // Return None if we have no permission to do that.
+    fn gen_overlayfs_upper(name: &str, whiteouts: &[&str], opaques: &[&str]) -> Option<PathBuf> {
+        let upper_path = test_dir(name);
+        for whiteout in whiteouts {
+            let path = upper_path.join(whiteout);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            let mode = Mode::from_bits_truncate(0o644);
+            if stat::mknod(&path, SFlag::S_IFCHR, mode, stat::makedev(0, 0)).is_err() {
+                println!("no permission to create overlayfs whiteout, skip");
+                return None;
+            }
+        }
+        for opaque in opaques {
+            let path = upper_path.join(opaque);
+            fs::create_dir_all(&path).unwrap();
+            if xattr::set(&path, OVERLAYFS_WHITEOUT_OPAQUE, b"y").is_err() {
+                println!("no permission to set overlayfs opaque, skip");
+                return None;
+            }
+        }
+        Some(upper_path)
+    }
+
+    #[test]
+    fn test_common_merge() {
+        let build = merge_example("example1");
+        assert_eq!(
+            tree_paths(&build.base_tree),
+            vec![
+                "/a",
+                "/a/a",
+                "/a/a/file1",
+                "/a/b",
+                "/a/b/file1",
+                "/a/file1",
+                "/b",
+                "/b/file2",
+                "/c",
+                "/c/file3",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_same_name_dir_merge() {
+        let build = merge_example("example9");
+        // /opt/lib/file3 must not leak into /usr/lib
+        assert_eq!(
+            tree_paths(&build.base_tree),
+            vec![
+                "/opt",
+                "/opt/lib",
+                "/opt/lib/file2",
+                "/opt/lib/file3",
+                "/usr",
+                "/usr/lib",
+                "/usr/lib/file1",
+            ]
+        );
     }
 
     #[test]
     fn test_upper_file_to_replace_base() {
-        let base_path = PathBuf::from("./file-example/example2/base-dir");
-        let base_tree =
-            FileSystemTree::build_from_file_system(base_path, Overlay::Lower, WhiteoutSpec::Oci)
-                .unwrap();
-        println!("show base tree");
-        base_tree.display_file_tree();
+        let build = merge_example("example2");
+        // upper file b replaces lower dir b with its subtree
+        assert!(tree_node(&build.base_tree, "/b").unwrap().is_general_file());
+        assert_eq!(
+            tree_paths(&build.base_tree),
+            vec!["/a", "/a/file1", "/a/file4", "/b", "/c", "/c/file3"]
+        );
+    }
 
-        let upper_path = PathBuf::from("./file-example/example2/upper-dir");
-        let upper_tree =
-            FileSystemTree::build_from_file_system(upper_path, Overlay::None, WhiteoutSpec::Oci)
-                .unwrap();
-        println!("show upper tree");
-        upper_tree.display_file_tree();
+    #[test]
+    fn test_upper_dir_to_replace_base() {
+        let build = merge_example("example3");
+        // upper dir c replaces lower file c, upper file b replaces lower dir b
+        assert!(tree_node(&build.base_tree, "/c").unwrap().is_directory());
+        assert!(tree_node(&build.base_tree, "/b").unwrap().is_general_file());
+        assert_eq!(
+            tree_paths(&build.base_tree),
+            vec!["/a", "/a/file1", "/a/file4", "/b", "/c", "/c/file3"]
+        );
+    }
 
-        let mut build = BuildTree::new(base_tree);
-        build.apply_tree_by_dfs(upper_tree.data.root(), 0, WhiteoutSpec::Oci);
+    #[test]
+    fn test_upper_symlink_to_replace_base_dir() {
+        let build = merge_example("example10");
+        let b = tree_node(&build.base_tree, "/b").unwrap();
+        assert!(b.is_symlink());
+        assert_eq!(b.link_target, Some(PathBuf::from("a/file4")));
+        assert_eq!(
+            tree_paths(&build.base_tree),
+            vec!["/a", "/a/file1", "/a/file4", "/b"]
+        );
+    }
 
-        println!("show merge tree");
-        build.display_base_tree()
+    #[test]
+    fn test_upper_dir_to_replace_base_symlink() {
+        let build = merge_example("example11");
+        assert!(tree_node(&build.base_tree, "/c").unwrap().is_directory());
+        assert_eq!(
+            tree_paths(&build.base_tree),
+            vec!["/a", "/a/file1", "/b", "/b/file2", "/c", "/c/file3"]
+        );
     }
 
     #[test]
-    fn test_upper_dir_to_replace_base() {
-        let base_path = PathBuf::from("./file-example/example3/base-dir");
-        let base_tree =
-            FileSystemTree::build_from_file_system(base_path, Overlay::Lower, WhiteoutSpec::Oci)
-                .unwrap();
-        println!("show base tree");
-        base_tree.display_file_tree();
+    fn test_oci_upper_remove() {
+        let build = merge_example("example4");
+        // a/.wh.file4 removes nothing, it is reported and never merged
+        assert_eq!(tree_paths(&build.base_tree), vec!["/a"]);
+        assert_eq!(build.diagnostics.len(), 1);
+        assert!(build.diagnostics[0].contains("/a/.wh.file4"));
+    }
 
-        let upper_path = PathBuf::from("./file-example/example3/upper-dir");
-        let upper_tree =
-            FileSystemTree::build_from_file_system(upper_path, Overlay::None, WhiteoutSpec::Oci)
-                .unwrap();
-        println!("show upper tree");
-        upper_tree.display_file_tree();
+    #[test]
+    fn test_oci_upper_remove_strict() {
+        let example = Path::new("./file-example/example4");
+        let err = merge_dirs(
+            &example.join("base-dir"),
+            &[example.join("upper-dir")],
+            WhiteoutSpec::Oci,
+            |build| build.orphan_whiteout = OrphanWhiteout::Strict,
+        )
+        .err()
+        .unwrap();
+        assert!(matches!(err, MergeTreeError::OrphanWhiteout { .. }));
+        assert!(err.to_string().contains("/a/.wh.file4"));
+    }
 
-        let mut build = BuildTree::new(base_tree);
-        build.apply_tree_by_dfs(upper_tree.data.root(), 0, WhiteoutSpec::Oci);
+    #[test]
+    fn test_oci_upper_dir_opaque() {
+        let build = merge_example("example5");
+        // lower children of a are hidden, a and a/file3 of the same layer are kept
+        assert!(tree_node(&build.base_tree, "/a").unwrap().is_opaque());
+        assert_eq!(
+            tree_paths(&build.base_tree),
+            vec!["/a", "/a/file3", "/b", "/b/file2", "/c", "/c/file1"]
+        );
+    }
 
-        println!("show merge tree");
-        build.display_base_tree()
+    #[test]
+    fn test_oci_upper_dir_opaque_with_whiteout() {
+        let base_path = test_dir("opaque-whiteout-base");
+        fs::create_dir(base_path.join("a")).unwrap();
+        fs::write(base_path.join("a/file1"), "").unwrap();
+        fs::write(base_path.join("a/file2"), "").unwrap();
+
+        // image tools may emit whiteouts of lower files next to the opaque
+        // marker of their dir
+        let upper_path = test_dir("opaque-whiteout-upper");
+        fs::create_dir(upper_path.join("a")).unwrap();
+        fs::write(upper_path.join("a/.wh..wh..opq"), "").unwrap();
+        fs::write(upper_path.join("a/.wh.file1"), "").unwrap();
+        fs::write(upper_path.join("a/file3"), "").unwrap();
+
+        let build = merge_dirs(&base_path, &[upper_path], WhiteoutSpec::Oci, |build| {
+            build.orphan_whiteout = OrphanWhiteout::Strict
+        })
+        .unwrap();
+        assert!(build.diagnostics.is_empty());
+        assert_eq!(tree_paths(&build.base_tree), vec!["/a", "/a/file3"]);
+    }
+
+    // merge the three upper dirs of example6 into its base-dir
+    fn merge_example6() -> BuildTree {
+        let example = Path::new("./file-example/example6");
+        let upper_paths: Vec<PathBuf> = (1..4)
+            .map(|i| example.join(format!("upper{}-dir", i)))
+            .collect();
+        merge_dirs(
+            &example.join("base-dir"),
+            &upper_paths,
+            WhiteoutSpec::Oci,
+            |_| {},
+        )
+        .unwrap()
     }
 
     #[test]
-    fn test_oci_upper_remove() {
-        let base_path = PathBuf::from("./file-example/example4/base-dir");
-        let base_tree =
-            FileSystemTree::build_from_file_system(base_path, Overlay::Lower, WhiteoutSpec::Oci)
-                .unwrap();
-        println!("show base tree");
-        base_tree.display_file_tree();
+    fn test_multi_upper_dir_merge() {
+        let build = merge_example6();
+        assert_eq!(
+            build.base_tree.format_file_tree(),
+            "/
+├── a
+│  ├── a
+│  │  ├── file1
+│  ├── b
+│  │  ├── file1
+│  ├── c
+│  │  ├── file1
+│  ├── d
+│  │  ├── file1
+│  ├── file1
+├── b
+│  ├── file2
+├── c
+│  ├── file3
+├── d
+│  ├── file3
+├── e
+│  ├── file3
+"
+        );
+    }
 
-        let upper_path = PathBuf::from("./file-example/example4/upper-dir");
-        let upper_tree =
-            FileSystemTree::build_from_file_system(upper_path, Overlay::None, WhiteoutSpec::Oci)
-                .unwrap();
-        println!("show upper tree");
-        upper_tree.display_file_tree();
+    #[test]
+    fn test_multi_upper_dir_layer_provenance() {
+        let build = merge_example6();
+        build.display_base_tree_with_option(&DisplayOption { show_layer: true });
 
-        let mut build = BuildTree::new(base_tree);
-        build.apply_tree_by_dfs(upper_tree.data.root(), 0, WhiteoutSpec::Oci);
+        let tree = &build.base_tree;
+        for (path, layer, shadowed) in [
+            ("/", 3, vec![0, 1, 2]),
+            ("/a", 3, vec![0, 1, 2]),
+            ("/a/file1", 0, vec![]),
+            ("/a/b/file1", 1, vec![]),
+            ("/d/file3", 2, vec![]),
+            ("/e", 3, vec![]),
+        ] {
+            let node = tree.lookup(Path::new(path)).unwrap().data();
+            assert_eq!(node.layer, layer, "layer of {}", path);
+            assert_eq!(node.shadowed, shadowed, "shadowed of {}", path);
+        }
+        assert_eq!(
+            tree.layer_path(2),
+            Some(Path::new("./file-example/example6/upper2-dir"))
+        );
+    }
 
-        println!("show merge tree");
-        build.display_base_tree()
+    #[test]
+    fn test_replaced_node_layer_provenance() {
+        let build = merge_example("example2");
+        let b = build.base_tree.lookup(Path::new("/b")).unwrap().data();
+        assert_eq!(b.layer, 1);
+        assert_eq!(b.shadowed, vec![0]);
+        let output = build
+            .base_tree
+            .format_file_tree_with_option(&DisplayOption { show_layer: true });
+        assert!(output.contains("├── b [layer 1 ./file-example/example2/upper-dir] [shadows 0]\n"));
     }
 
     #[test]
-    fn test_oci_upper_dir_opaque() {
-        let base_path = PathBuf::from("./file-example/example5/base-dir");
-        let base_tree =
-            FileSystemTree::build_from_file_system(base_path, Overlay::Lower, WhiteoutSpec::Oci)
-                .unwrap();
-        println!("show base tree");
-        base_tree.display_file_tree();
+    fn test_insertion_child_order() {
+        let option = ScanOption {
+            child_order: ChildOrder::Insertion,
+            ..ScanOption::default()
+        };
+        let example = Path::new("./file-example/example1");
+        let build = merge_dirs_with_option(
+            &example.join("base-dir"),
+            &[example.join("upper-dir")],
+            WhiteoutSpec::Oci,
+            &option,
+            |_| {},
+        )
+        .unwrap();
 
-        let upper_path = PathBuf::from("./file-example/example5/upper-dir");
-        let upper_tree =
-            FileSystemTree::build_from_file_system(upper_path, Overlay::None, WhiteoutSpec::Oci)
-                .unwrap();
-        println!("show upper tree");
-        upper_tree.display_file_tree();
+        // added entries are appended after lower ones instead of sorted
+        let root = build.base_tree.data.root();
+        assert_eq!(root.back().unwrap().data().name, "c");
+        let a = root.iter().find(|node| node.data().name == "a").unwrap();
+        assert_eq!(a.back().unwrap().data().name, "b");
+    }
 
-        let mut build = BuildTree::new(base_tree);
-        build.apply_tree_by_dfs(upper_tree.data.root(), 0, WhiteoutSpec::Oci);
+    #[test]
+    fn test_overlayfs_upper_remove() {
+        let whiteouts = &["a/file1", "b/file2", "b/file3"];
+        let upper_path = match gen_overlayfs_upper("example7", whiteouts, &[]) {
+            Some(path) => path,
+            None => return,
+        };
+        let build = merge_dirs(
+            Path::new("./file-example/example7/base-dir"),
+            &[upper_path],
+            WhiteoutSpec::Overlayfs,
+            |_| {},
+        )
+        .unwrap();
+        // b/file3 removes nothing, it is reported and never merged
+        assert_eq!(
+            tree_paths(&build.base_tree),
+            vec!["/a", "/a/a", "/a/a/file1", "/b"]
+        );
+        assert_eq!(build.diagnostics.len(), 1);
+        assert!(build.diagnostics[0].contains("/b/file3"));
+    }
 
-        println!("show merge tree");
-        build.display_base_tree()
+    #[test]
+    fn test_overlayfs_upper_dir_opaque() {
+        let upper_path = match gen_overlayfs_upper("example8", &["a/file1", "b/file2"], &["c"]) {
+            Some(path) => path,
+            None => return,
+        };
+        fs::write(upper_path.join("c/file4"), "").unwrap();
+        let build = merge_dirs(
+            Path::new("./file-example/example8/base-dir"),
+            &[upper_path],
+            WhiteoutSpec::Overlayfs,
+            |_| {},
+        )
+        .unwrap();
+        // lower children of c are hidden, c and c/file4 of the same layer are kept
+        assert!(tree_node(&build.base_tree, "/c").unwrap().is_opaque());
+        assert_eq!(
+            tree_paths(&build.base_tree),
+            vec!["/a", "/a/a", "/a/a/file1", "/b", "/c", "/c/file4"]
+        );
     }
 
     #[test]
-    fn test_multi_upper_dir_merge() {
-        //base tree and create build
-        let base_path = PathBuf::from("./file-example/example6/base-dir");
-        let base_tree =
-            FileSystemTree::build_from_file_system(base_path, Overlay::Lower, WhiteoutSpec::Oci)
-                .unwrap();
-        println!("show base tree");
-        base_tree.display_file_tree();
+    fn test_upper_dir_meta_override_base() {
+        let base_path = test_dir("dir-meta-base");
+        fs::create_dir(base_path.join("etc")).unwrap();
+        fs::write(base_path.join("etc/passwd"), "").unwrap();
+        fs::set_permissions(base_path.join("etc"), fs::Permissions::from_mode(0o755)).unwrap();
 
-        let mut build = BuildTree::new(base_tree);
+        let upper_path = test_dir("dir-meta-upper");
+        fs::create_dir(upper_path.join("etc")).unwrap();
+        fs::write(upper_path.join("etc/group"), "").unwrap();
+        fs::set_permissions(upper_path.join("etc"), fs::Permissions::from_mode(0o700)).unwrap();
+        // not every file system supports user xattrs
+        let has_xattr = xattr::set(upper_path.join("etc"), "user.merge-tree", b"upper").is_ok();
+        let upper_meta = fs::metadata(upper_path.join("etc")).unwrap();
+
+        let build = merge_dirs(
+            &base_path,
+            std::slice::from_ref(&upper_path),
+            WhiteoutSpec::Oci,
+            |_| {},
+        )
+        .unwrap();
+
+        let etc = tree_node(&build.base_tree, "/etc").unwrap();
+        assert_eq!(etc.source, upper_path.join("etc"));
+        assert_eq!(etc.meta.mode, 0o700);
+        assert_eq!(etc.meta.uid, upper_meta.uid());
+        assert_eq!(etc.meta.gid, upper_meta.gid());
+        assert_eq!(etc.meta.mtime, upper_meta.mtime());
+        assert_eq!(etc.meta.mtime_nsec, upper_meta.mtime_nsec());
+        if has_xattr {
+            assert_eq!(
+                etc.xattrs.get(&OsString::from("user.merge-tree")),
+                Some(&b"upper".to_vec())
+            );
+        }
+        assert_eq!(
+            tree_paths(&build.base_tree),
+            vec!["/etc", "/etc/group", "/etc/passwd"]
+        );
+    }
+
+    #[test]
+    fn test_upper_addition_keep_full_node() {
+        let base_path = test_dir("addition-base");
+        fs::create_dir(base_path.join("a")).unwrap();
+        fs::create_dir(base_path.join("b")).unwrap();
+        fs::write(base_path.join("b/file1"), "").unwrap();
+
+        let upper_path = test_dir("addition-upper");
+        fs::create_dir_all(upper_path.join("c/d")).unwrap();
+        fs::write(upper_path.join("c/d/file2"), "").unwrap();
+        // upper file b replaces lower dir b
+        fs::write(upper_path.join("b"), "").unwrap();
+        // not every file system supports user xattrs
+        let has_xattr = xattr::set(upper_path.join("c"), "user.merge-tree", b"c").is_ok()
+            && xattr::set(upper_path.join("b"), "user.merge-tree", b"b").is_ok();
+
+        let build = merge_dirs(
+            &base_path,
+            std::slice::from_ref(&upper_path),
+            WhiteoutSpec::Oci,
+            |_| {},
+        )
+        .unwrap();
 
-        for i in 1..4 {
-            let path = format!("./file-example/example6/upper{}-dir", i);
-            let upper_path = PathBuf::from(path);
-            let upper_tree = FileSystemTree::build_from_file_system(
+        assert_eq!(
+            tree_paths(&build.base_tree),
+            vec!["/a", "/b", "/c", "/c/d", "/c/d/file2"]
+        );
+        for (path, source) in [
+            ("/b", "b"),
+            ("/c", "c"),
+            ("/c/d", "c/d"),
+            ("/c/d/file2", "c/d/file2"),
+        ] {
+            let node = tree_node(&build.base_tree, path).unwrap();
+            assert!(node.overlay == Overlay::UpperAddition);
+            assert_eq!(node.source, upper_path.join(source));
+        }
+        if has_xattr {
+            for (path, value) in [("/b", b"b"), ("/c", b"c")] {
+                let node = tree_node(&build.base_tree, path).unwrap();
+                assert_eq!(
+                    node.xattrs.get(&OsString::from("user.merge-tree")),
+                    Some(&value.to_vec())
+                );
+            }
+        }
+    }
+
+    // reorder children of every dir in tree by xorshift
+    fn shuffle_children(node: &mut Node<TreeNode>, seed: &mut u64) {
+        let mut children = Vec::new();
+        while let Some(child) = node.pop_front() {
+            children.push(child);
+        }
+        for i in (1..children.len()).rev() {
+            *seed ^= *seed << 13;
+            *seed ^= *seed >> 7;
+            *seed ^= *seed << 17;
+            children.swap(i, (*seed % (i as u64 + 1)) as usize);
+        }
+        for mut child in children {
+            shuffle_children(child.root_mut().get_mut(), seed);
+            node.push_back(child);
+        }
+    }
+
+    #[test]
+    fn test_whiteout_apply_to_lower_layer_only() {
+        for round in 1..=16 {
+            let base_path = PathBuf::from("./file-example/example12/base-dir");
+            let base_tree = FileSystemTree::build_from_file_system(
+                base_path,
+                Overlay::Lower,
+                WhiteoutSpec::Oci,
+            )
+            .unwrap();
+            let upper_path = PathBuf::from("./file-example/example12/upper-dir");
+            let mut upper_tree = FileSystemTree::build_from_file_system(
                 upper_path,
                 Overlay::None,
                 WhiteoutSpec::Oci,
             )
             .unwrap();
-            println!("show upper tree {}", i);
+            let mut seed = round;
+            shuffle_children(upper_tree.data.root_mut().get_mut(), &mut seed);
+            println!("show upper tree of round {}", round);
             upper_tree.display_file_tree();
-            build.apply_tree_by_dfs(upper_tree.data.root(), 0, WhiteoutSpec::Oci);
+
+            let mut build = BuildTree::new(base_tree);
+            build
+                .apply_tree_by_dfs(&upper_tree, WhiteoutSpec::Oci)
+                .unwrap();
+
+            // .wh.b, a/.wh..wh..opq and c/.wh.file4 never hide b, a/file5 and
+            // c/file4 of the same layer
+            assert_eq!(
+                tree_paths(&build.base_tree),
+                vec!["/a", "/a/file5", "/b", "/b/file6", "/c", "/c/file4"]
+            );
+            let file4 = tree_node(&build.base_tree, "/c/file4").unwrap();
+            assert!(file4.overlay == Overlay::UpperAddition);
+            assert!(build.diagnostics.is_empty());
         }
-        println!("show merge tree");
-        build.display_base_tree()
     }
 
     #[test]
-    /// Create overlay whiteout first
-    fn test_overlayfs_upper_remove() {
-        let base_path = PathBuf::from("./file-example/example7/base-dir");
-        let base_tree = FileSystemTree::build_from_file_system(
-            base_path,
-            Overlay::Lower,
+    fn test_upper_root_meta_override_base() {
+        let base_path = test_dir("root-meta-base");
+        fs::write(base_path.join("file1"), "").unwrap();
+        fs::set_permissions(&base_path, fs::Permissions::from_mode(0o755)).unwrap();
+
+        let upper_path = test_dir("root-meta-upper");
+        fs::write(upper_path.join("file2"), "").unwrap();
+        fs::set_permissions(&upper_path, fs::Permissions::from_mode(0o700)).unwrap();
+        // not every file system supports user xattrs
+        let has_xattr = xattr::set(&upper_path, "user.merge-tree", b"root").is_ok();
+        let upper_meta = fs::metadata(&upper_path).unwrap();
+
+        let build = merge_dirs(&base_path, &[upper_path], WhiteoutSpec::Oci, |_| {}).unwrap();
+
+        let root = tree_node(&build.base_tree, "/").unwrap();
+        assert_eq!(root.meta.mode, 0o700);
+        assert_eq!(root.meta.uid, upper_meta.uid());
+        assert_eq!(root.meta.mtime, upper_meta.mtime());
+        if has_xattr {
+            assert_eq!(
+                root.xattrs.get(&OsString::from("user.merge-tree")),
+                Some(&b"root".to_vec())
+            );
+        }
+        assert_eq!(tree_paths(&build.base_tree), vec!["/file1", "/file2"]);
+    }
+
+    #[test]
+    fn test_oci_upper_root_opaque() {
+        let build = merge_example("example13");
+        assert!(tree_node(&build.base_tree, "/").unwrap().is_opaque());
+        assert_eq!(tree_paths(&build.base_tree), vec!["/c", "/c/file3"]);
+    }
+
+    #[test]
+    fn test_overlayfs_upper_root_opaque() {
+        let upper_path = match gen_overlayfs_upper("root-opaque", &[], &[""]) {
+            Some(path) => path,
+            None => return,
+        };
+        fs::create_dir(upper_path.join("c")).unwrap();
+        fs::write(upper_path.join("c/file3"), "").unwrap();
+        let build = merge_dirs(
+            Path::new("./file-example/example8/base-dir"),
+            &[upper_path],
             WhiteoutSpec::Overlayfs,
+            |_| {},
         )
         .unwrap();
-        println!("show base tree");
-        base_tree.display_file_tree();
 
-        let upper_path = PathBuf::from("./file-example/example7/upper-dir");
-        let upper_tree = FileSystemTree::build_from_file_system(
-            upper_path,
-            Overlay::None,
-            WhiteoutSpec::Overlayfs,
+        assert!(tree_node(&build.base_tree, "/").unwrap().is_opaque());
+        assert_eq!(tree_paths(&build.base_tree), vec!["/c", "/c/file3"]);
+    }
+
+    #[test]
+    fn test_symlink_merge() {
+        let build = merge_example("example14");
+        // links are never followed, /bin is not scanned into host /usr/bin,
+        // and dangling links are kept
+        assert_eq!(
+            build.base_tree.format_file_tree(),
+            "/
+├── bin -> /usr/bin
+├── etc -> /nonexistent
+├── lib
+│  ├── file2
+├── run -> missing
+├── usr
+│  ├── file1 -> ../lib/file1
+│  ├── lib
+│  │  ├── file1
+"
+        );
+        for path in ["/bin", "/etc", "/run", "/usr/file1"] {
+            assert!(tree_node(&build.base_tree, path).unwrap().is_symlink());
+        }
+    }
+
+    #[test]
+    fn test_hardlink_merge() {
+        let base_path = gen_hardlink_dir(
+            "hardlink-merge-base",
+            &["a/file1", "b/file2"],
+            &[
+                ("a/file1", "a/file3"),
+                ("b/file2", "b/file4"),
+                ("b/file2", "b/file5"),
+            ],
+        );
+        let upper_path = gen_hardlink_dir(
+            "hardlink-merge-upper",
+            &["a/.wh.file3", "b/file4", "c/file6"],
+            &[("c/file6", "c/file7")],
+        );
+        let build = merge_dirs(&base_path, &[upper_path], WhiteoutSpec::Oci, |_| {}).unwrap();
+
+        // a whiteout removes one of two names, a/file1 is a plain file now
+        assert!(tree_node(&build.base_tree, "/a/file1")
+            .unwrap()
+            .hardlink
+            .is_none());
+        // upper replaces one of three names, the other two are still linked,
+        // names of upper layer are grouped in that layer
+        let groups: Vec<Vec<PathBuf>> = build.base_tree.hardlink_groups().into_values().collect();
+        assert_eq!(
+            groups,
+            vec![
+                vec![PathBuf::from("/b/file2"), PathBuf::from("/b/file5")],
+                vec![PathBuf::from("/c/file6"), PathBuf::from("/c/file7")],
+            ]
+        );
+        assert!(tree_node(&build.base_tree, "/b/file4")
+            .unwrap()
+            .hardlink
+            .is_none());
+        assert_eq!(
+            tree_node(&build.base_tree, "/c/file7")
+                .unwrap()
+                .hardlink
+                .unwrap()
+                .layer,
+            1
+        );
+    }
+
+    #[test]
+    fn test_non_utf8_name_merge() {
+        // non-UTF-8 names can't be kept in git, create them before testing
+        let base_path = test_dir("non-utf8-base");
+        let upper_path = test_dir("non-utf8-upper");
+        let latin1 = OsStr::from_bytes(b"caf\xe9");
+        fs::create_dir(base_path.join(latin1)).unwrap();
+        fs::write(base_path.join(latin1).join("file1"), b"").unwrap();
+        fs::write(base_path.join(OsStr::from_bytes(b"\xff\xfe")), b"").unwrap();
+        fs::write(base_path.join("back\\slash"), b"").unwrap();
+        fs::create_dir(upper_path.join(latin1)).unwrap();
+        fs::write(
+            upper_path
+                .join(latin1)
+                .join(OsStr::from_bytes(b"new\nline")),
+            b"",
         )
         .unwrap();
-        println!("show upper tree");
-        upper_tree.display_file_tree();
+        fs::write(upper_path.join(OsStr::from_bytes(b".wh.\xff\xfe")), b"").unwrap();
 
+        let build = merge_dirs(&base_path, &[upper_path], WhiteoutSpec::Oci, |_| {}).unwrap();
+
+        // whiteout prefix is matched on bytes and names are kept as they are
+        assert!(build.diagnostics.is_empty());
+        let file1 = Path::new(latin1).join("file1");
+        assert!(build
+            .base_tree
+            .lookup(&Path::new("/").join(file1))
+            .is_some());
+        assert_eq!(
+            build.base_tree.format_file_tree(),
+            "/
+├── back\\\\slash
+├── caf\\xe9
+│  ├── file1
+│  ├── new\\x0aline
+"
+        );
+    }
+
+    #[test]
+    fn test_invalid_whiteout() {
+        let base_path = PathBuf::from("./file-example/example4/base-dir");
+        let upper_path = PathBuf::from("./file-example/example4/upper-dir");
+        // upper is scanned with OCI spec but merged with overlayfs spec
+        let base_tree =
+            FileSystemTree::build_from_file_system(base_path, Overlay::Lower, WhiteoutSpec::Oci)
+                .unwrap();
+        let upper_tree =
+            FileSystemTree::build_from_file_system(upper_path, Overlay::None, WhiteoutSpec::Oci)
+                .unwrap();
         let mut build = BuildTree::new(base_tree);
-        build.apply_tree_by_dfs(upper_tree.data.root(), 0, WhiteoutSpec::Overlayfs);
+        let err = build
+            .apply_tree_by_dfs(&upper_tree, WhiteoutSpec::Overlayfs)
+            .unwrap_err();
+        assert!(matches!(err, MergeTreeError::InvalidWhiteout { .. }));
+        assert!(err.to_string().contains("/.wh.b"));
 
-        println!("show merge tree");
-        build.display_base_tree()
+        // whiteout without target name
+        let upper_path = test_dir("empty-whiteout");
+        fs::write(upper_path.join(".wh."), b"").unwrap();
+        let err = merge_dirs(
+            Path::new("./file-example/example4/base-dir"),
+            &[upper_path],
+            WhiteoutSpec::Oci,
+            |_| {},
+        )
+        .err()
+        .unwrap();
+        assert!(matches!(err, MergeTreeError::InvalidWhiteout { .. }));
     }
 
     #[test]
-    /// Create overlay whiteout first
-    fn test_overlayfs_upper_dir_opaque() {
-        let base_path = PathBuf::from("./file-example/example8/base-dir");
-        let base_tree = FileSystemTree::build_from_file_system(
-            base_path,
-            Overlay::Lower,
-            WhiteoutSpec::Overlayfs,
+    fn test_in_memory_tree_merge() {
+        let dir = NodeMeta::new(FileType::Directory, 0o755);
+        let file = NodeMeta::new(FileType::Regular, 0o644);
+        let mut base = FileSystemTree::new(
+            mem_node("/", dir.clone(), Overlay::Lower),
+            PathBuf::from("base"),
+            ChildOrder::Sorted,
+        );
+        for (path, meta) in [
+            ("/etc", &dir),
+            ("/etc/passwd", &file),
+            ("/etc/shadow", &file),
+            ("/usr", &dir),
+        ] {
+            let name = Path::new(path).file_name().unwrap().to_str().unwrap();
+            assert!(base.insert(
+                Path::new(path),
+                mem_node(name, meta.clone(), Overlay::Lower)
+            ));
+        }
+        // parent must exist
+        assert!(!base.insert(
+            Path::new("/opt/file"),
+            mem_node("file", file.clone(), Overlay::Lower)
+        ));
+
+        // overlayfs whiteout is a char device 0/0, no root needed in memory
+        let mut upper = FileSystemTree::new(
+            mem_node("/", dir.clone(), Overlay::None),
+            PathBuf::from("upper"),
+            ChildOrder::Sorted,
+        );
+        let etc = NodeMeta {
+            mode: 0o700,
+            uid: 1000,
+            mtime: 1_600_000_000,
+            mtime_nsec: 42,
+            ..dir.clone()
+        };
+        let whiteout = NodeMeta::new(FileType::CharDevice, 0);
+        let device = NodeMeta {
+            rdev: stat::makedev(1, 3),
+            ..NodeMeta::new(FileType::CharDevice, 0o666)
+        };
+        for (path, meta) in [
+            ("/etc", etc.clone()),
+            ("/etc/shadow", whiteout),
+            ("/etc/null", device),
+        ] {
+            let name = Path::new(path).file_name().unwrap().to_str().unwrap();
+            assert!(upper.insert(Path::new(path), mem_node(name, meta, Overlay::None)));
+        }
+
+        let mut build = BuildTree::new(base);
+        build
+            .apply_tree_by_dfs(&upper, WhiteoutSpec::Overlayfs)
+            .unwrap();
+        assert_eq!(
+            tree_paths(&build.base_tree),
+            vec!["/etc", "/etc/null", "/etc/passwd", "/usr"]
+        );
+        assert_eq!(tree_node(&build.base_tree, "/etc").unwrap().meta, etc);
+        // meta is kept the same through serde
+        let json = serde_json::to_string(&etc).unwrap();
+        assert_eq!(serde_json::from_str::<NodeMeta>(&json).unwrap(), etc);
+        assert_eq!(
+            tree_node(&build.base_tree, "/etc/null")
+                .unwrap()
+                .meta
+                .major(),
+            1
+        );
+    }
+
+    // chain of nested dirs "/d/d/.../d/file" built in memory without recursion
+    fn deep_tree(depth: usize, file: &str, overlay: Overlay, layer_path: &str) -> FileSystemTree {
+        let dir = NodeMeta::new(FileType::Directory, 0o755);
+        let mut tree = Tree::new(mem_node(
+            file,
+            NodeMeta::new(FileType::Regular, 0o644),
+            overlay,
+        ));
+        for _ in 0..depth {
+            let mut parent = Tree::new(mem_node("d", dir.clone(), overlay));
+            parent.push_back(tree);
+            tree = parent;
+        }
+        let mut fs_tree = FileSystemTree::new(
+            mem_node("/", dir, overlay),
+            PathBuf::from(layer_path),
+            ChildOrder::Sorted,
+        );
+        fs_tree.data.push_back(tree);
+        fs_tree
+    }
+
+    #[test]
+    fn test_deep_tree_merge() {
+        // deep enough to overflow the stack of test thread by recursion
+        let depth = 50_000;
+        let base = deep_tree(depth, "file1", Overlay::Lower, "base");
+        let upper = deep_tree(depth, "file2", Overlay::None, "upper");
+        let mut build = BuildTree::new(base);
+        build.apply_tree_by_dfs(&upper, WhiteoutSpec::Oci).unwrap();
+
+        let mut count = 0;
+        let mut deepest = PathBuf::new();
+        build.base_tree.walk(|path, _| {
+            count += 1;
+            deepest = path.to_path_buf();
+        });
+        // root, dirs and two files at the bottom
+        assert_eq!(count, 1 + depth + 2);
+        assert_eq!(deepest.components().count(), 1 + depth + 1);
+        assert_eq!(deepest.file_name().unwrap(), "file2");
+        assert!(build.base_tree.hardlink_groups().is_empty());
+    }
+
+    #[test]
+    fn test_mount_point_merge() {
+        let dir = NodeMeta::new(FileType::Directory, 0o755);
+        let file = NodeMeta::new(FileType::Regular, 0o644);
+        let mut base = FileSystemTree::new(
+            mem_node("/", dir.clone(), Overlay::Lower),
+            PathBuf::from("base"),
+            ChildOrder::Sorted,
+        );
+        assert!(base.insert(
+            Path::new("/proc"),
+            mem_node("proc", dir.clone(), Overlay::Lower)
+        ));
+        assert!(base.insert(
+            Path::new("/proc/file1"),
+            mem_node("file1", file, Overlay::Lower)
+        ));
+        let mut upper = FileSystemTree::new(
+            mem_node("/", dir.clone(), Overlay::None),
+            PathBuf::from("upper"),
+            ChildOrder::Sorted,
+        );
+        let mut proc = mem_node("proc", dir, Overlay::None);
+        proc.mount_point = true;
+        assert!(upper.insert(Path::new("/proc"), proc));
+
+        // mounted file system hides lower entries under the mount point
+        let mut build = BuildTree::new(base);
+        build.apply_tree_by_dfs(&upper, WhiteoutSpec::Oci).unwrap();
+        assert_eq!(tree_paths(&build.base_tree), vec!["/proc"]);
+        let proc = tree_node(&build.base_tree, "/proc").unwrap();
+        assert!(proc.mount_point);
+        assert!(proc.is_opaque());
+    }
+
+    #[test]
+    fn test_special_file_merge() {
+        let upper_path = test_dir("special-upper");
+        nix::unistd::mkfifo(&upper_path.join("fifo"), Mode::from_bits_truncate(0o644)).unwrap();
+        let _socket = std::os::unix::net::UnixListener::bind(upper_path.join("socket")).unwrap();
+        let mode = Mode::from_bits_truncate(0o666);
+        for (name, kind, major, minor) in [
+            ("null", SFlag::S_IFCHR, 1, 3),
+            ("loop0", SFlag::S_IFBLK, 7, 0),
+            // char device 0/0 is a whiteout of overlayfs only
+            ("b", SFlag::S_IFCHR, 0, 0),
+        ] {
+            let path = upper_path.join(name);
+            if stat::mknod(&path, kind, mode, stat::makedev(major, minor)).is_err() {
+                println!("no permission to create device, skip");
+                return;
+            }
+        }
+        let build = merge_dirs(
+            Path::new("./file-example/example1/base-dir"),
+            &[upper_path],
+            WhiteoutSpec::Oci,
+            |_| {},
         )
         .unwrap();
-        println!("show base tree");
-        base_tree.display_file_tree();
 
-        let upper_path = PathBuf::from("./file-example/example8/upper-dir");
-        let upper_tree = FileSystemTree::build_from_file_system(
-            upper_path,
-            Overlay::None,
+        // under OCI char device 0/0 replaces dir /b like any other file
+        assert_eq!(
+            build.base_tree.format_file_tree(),
+            "/
+├── a
+│  ├── a
+│  │  ├── file1
+│  ├── file1
+├── b [char 0:0]
+├── fifo [fifo]
+├── loop0 [block 7:0]
+├── null [char 1:3]
+├── socket [socket]
+"
+        );
+        let null = tree_node(&build.base_tree, "/null").unwrap();
+        assert_eq!(null.file_type(), FileType::CharDevice);
+        assert_eq!((null.meta.major(), null.meta.minor()), (1, 3));
+        assert!(null.is_device());
+        assert!(!null.is_whiteout());
+        let fifo = tree_node(&build.base_tree, "/fifo").unwrap();
+        assert!(fifo.is_special_file() && !fifo.is_device());
+        assert_eq!(
+            tree_node(&build.base_tree, "/socket").unwrap().file_type(),
+            FileType::Socket
+        );
+    }
+
+    #[test]
+    fn test_overlayfs_opaque_without_xattrs() {
+        let upper_path = match gen_overlayfs_upper("opaque-no-xattrs", &[], &["a"]) {
+            Some(path) => path,
+            None => return,
+        };
+        xattr::set(upper_path.join("a"), "user.keep", b"1").unwrap();
+        // opaque xattr is read even if it is filtered out
+        let option = ScanOption {
+            xattrs: XattrOption {
+                include: vec!["user.".to_string()],
+                ..XattrOption::default()
+            },
+            ..ScanOption::default()
+        };
+        let build = merge_dirs_with_option(
+            Path::new("./file-example/example1/base-dir"),
+            &[upper_path],
             WhiteoutSpec::Overlayfs,
+            &option,
+            |_| {},
         )
         .unwrap();
-        println!("show upper tree");
-        upper_tree.display_file_tree();
+        assert_eq!(tree_paths(&build.base_tree), vec!["/a", "/b", "/b/file2"]);
 
-        let mut build = BuildTree::new(base_tree);
-        build.apply_tree_by_dfs(upper_tree.data.root(), 0, WhiteoutSpec::Overlayfs);
+        // xattrs private to overlayfs are not shown in merged tree
+        let a = tree_node(&build.base_tree, "/a").unwrap();
+        assert!(a.is_opaque());
+        assert!(a
+            .xattrs
+            .get(&OsString::from(OVERLAYFS_WHITEOUT_OPAQUE))
+            .is_none());
+        assert_eq!(
+            a.xattrs.get(&OsString::from("user.keep")),
+            Some(&b"1".to_vec())
+        );
+    }
 
-        println!("show merge tree");
-        build.display_base_tree()
+    #[test]
+    fn test_wide_dir_merge() {
+        let dir = NodeMeta::new(FileType::Directory, 0o755);
+        let file = NodeMeta::new(FileType::Regular, 0o644);
+        let whiteout = NodeMeta::new(FileType::CharDevice, 0);
+        let mut base = FileSystemTree::new(
+            mem_node("/", dir.clone(), Overlay::Lower),
+            PathBuf::from("base"),
+            ChildOrder::Sorted,
+        );
+        assert!(base.insert(Path::new("/d"), mem_node("d", dir.clone(), Overlay::Lower)));
+        let mut expected = vec!["/d".to_string()];
+        // many children in a dir, lower ones are found by name index
+        for i in 0..40 {
+            let name = format!("file{:02}", i);
+            let path = format!("/d/{}", name);
+            assert!(base.insert(
+                Path::new(&path),
+                mem_node(&name, file.clone(), Overlay::Lower)
+            ));
+            if i != 5 {
+                expected.push(path);
+            }
+        }
+        assert!(base.insert(
+            Path::new("/d/sub"),
+            mem_node("sub", dir.clone(), Overlay::Lower)
+        ));
+        assert!(base.insert(
+            Path::new("/d/sub/x"),
+            mem_node("x", file.clone(), Overlay::Lower)
+        ));
+
+        let mut upper = FileSystemTree::new(
+            mem_node("/", dir.clone(), Overlay::None),
+            PathBuf::from("upper"),
+            ChildOrder::Sorted,
+        );
+        for (path, meta) in [
+            ("/d", &dir),
+            ("/d/file05", &whiteout),
+            ("/d/file10", &dir),
+            ("/d/file10/z", &file),
+            ("/d/file40", &file),
+            ("/d/sub", &dir),
+            ("/d/sub/y", &file),
+        ] {
+            let name = Path::new(path).file_name().unwrap().to_str().unwrap();
+            assert!(upper.insert(Path::new(path), mem_node(name, meta.clone(), Overlay::None)));
+        }
+
+        let mut build = BuildTree::new(base);
+        build
+            .apply_tree_by_dfs(&upper, WhiteoutSpec::Overlayfs)
+            .unwrap();
+        assert!(build.diagnostics.is_empty());
+        expected.extend(
+            ["/d/file10/z", "/d/file40", "/d/sub", "/d/sub/x", "/d/sub/y"]
+                .iter()
+                .map(|path| path.to_string()),
+        );
+        expected.sort();
+        assert_eq!(tree_paths(&build.base_tree), expected);
+        // additions are placed among lower children by name
+        let mut walked = Vec::new();
+        build
+            .base_tree
+            .walk(|path, _| walked.push(path.display().to_string()));
+        assert_eq!(walked[1..], expected[..]);
+        assert!(tree_node(&build.base_tree, "/d/file10")
+            .unwrap()
+            .is_directory());
+    }
+
+    #[test]
+    fn test_overlayfs_xattrs_by_whiteout_spec() {
+        let dir = NodeMeta::new(FileType::Directory, 0o755);
+        let file = NodeMeta::new(FileType::Regular, 0o644);
+        let origin = OsString::from("trusted.overlay.origin");
+        let merge = |whiteout_spec: WhiteoutSpec| {
+            let mut base = FileSystemTree::new(
+                mem_node("/", dir.clone(), Overlay::Lower),
+                PathBuf::from("base"),
+                ChildOrder::Sorted,
+            );
+            assert!(base.insert(
+                Path::new("/etc"),
+                mem_node("etc", dir.clone(), Overlay::Lower)
+            ));
+            let mut upper = FileSystemTree::new(
+                mem_node("/", dir.clone(), Overlay::None),
+                PathBuf::from("upper"),
+                ChildOrder::Sorted,
+            );
+            for (path, meta) in [("/etc", &dir), ("/etc/passwd", &file)] {
+                let name = Path::new(path).file_name().unwrap().to_str().unwrap();
+                let mut node = mem_node(name, meta.clone(), Overlay::None);
+                node.xattrs.add(origin.clone(), b"y".to_vec());
+                assert!(upper.insert(Path::new(path), node));
+            }
+            let mut build = BuildTree::new(base);
+            build.apply_tree_by_dfs(&upper, whiteout_spec).unwrap();
+            ["/etc", "/etc/passwd"]
+                .iter()
+                .map(|path| {
+                    let node = tree_node(&build.base_tree, path).unwrap();
+                    node.xattrs.get(&origin).is_some()
+                })
+                .collect::<Vec<_>>()
+        };
+
+        // xattrs private to overlayfs only mean something in an overlayfs
+        // upper dir, an OCI layer keeps them like any other xattr
+        assert_eq!(merge(WhiteoutSpec::Oci), vec![true, true]);
+        assert_eq!(merge(WhiteoutSpec::Overlayfs), vec![false, false]);
     }
 }
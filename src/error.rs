@@ -0,0 +1,148 @@
+use crate::tree::escape_name;
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+
+pub type Result<T> = std::result::Result<T, MergeTreeError>;
+
+#[derive(Debug)]
+pub enum MergeTreeError {
+    /// Failed to read a file or dir of a layer
+    Io { path: PathBuf, source: io::Error },
+    /// Failed to list or read xattrs of a file
+    Xattr { path: PathBuf, source: io::Error },
+    /// File type can't be handled there, e.g. layer root is not a dir
+    UnsupportedFileType { path: PathBuf, reason: String },
+    /// Whiteout entry which can't be applied by the whiteout spec
+    InvalidWhiteout { path: PathBuf, reason: String },
+    /// Whiteouts with nothing to remove in lower layers, in strict mode
+    OrphanWhiteout { paths: Vec<PathBuf> },
+    /// Entry deeper than ScanOption::max_depth
+    TooDeep { path: PathBuf, max_depth: usize },
+    /// Layer archive with an entry which can't be read into a tree
+    InvalidArchive { path: PathBuf, reason: String },
+    /// Image layout, index or manifest which can't be resolved into layers
+    InvalidImage { path: PathBuf, reason: String },
+    /// Blob or layer whose content doesn't match the digest of the image
+    DigestMismatch {
+        path: PathBuf,
+        expected: String,
+        actual: String,
+    },
+}
+
+impl MergeTreeError {
+    pub fn io(path: &Path, source: io::Error) -> Self {
+        MergeTreeError::Io {
+            path: path.to_path_buf(),
+            source,
+        }
+    }
+
+    pub fn xattr(path: &Path, source: io::Error) -> Self {
+        MergeTreeError::Xattr {
+            path: path.to_path_buf(),
+            source,
+        }
+    }
+
+    /// Exit code of the CLI for the error, distinct for every kind
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            MergeTreeError::Io { .. } => 2,
+            MergeTreeError::Xattr { .. } => 3,
+            MergeTreeError::UnsupportedFileType { .. } => 4,
+            MergeTreeError::InvalidWhiteout { .. } => 5,
+            MergeTreeError::OrphanWhiteout { .. } => 6,
+            MergeTreeError::TooDeep { .. } => 7,
+            MergeTreeError::InvalidArchive { .. } => 8,
+            MergeTreeError::InvalidImage { .. } => 9,
+            MergeTreeError::DigestMismatch { .. } => 10,
+        }
+    }
+}
+
+impl fmt::Display for MergeTreeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MergeTreeError::Io { path, source } => {
+                write!(
+                    f,
+                    "failed to read {}: {}",
+                    escape_name(path.as_os_str()),
+                    source
+                )
+            }
+            MergeTreeError::Xattr { path, source } => write!(
+                f,
+                "failed to read xattrs of {}: {}",
+                escape_name(path.as_os_str()),
+                source
+            ),
+            MergeTreeError::UnsupportedFileType { path, reason } => write!(
+                f,
+                "unsupported file type of {}: {}",
+                escape_name(path.as_os_str()),
+                reason
+            ),
+            MergeTreeError::InvalidWhiteout { path, reason } => write!(
+                f,
+                "invalid whiteout {}: {}",
+                escape_name(path.as_os_str()),
+                reason
+            ),
+            MergeTreeError::TooDeep { path, max_depth } => write!(
+                f,
+                "{} is deeper than max depth {}",
+                escape_name(path.as_os_str()),
+                max_depth
+            ),
+            MergeTreeError::InvalidArchive { path, reason } => write!(
+                f,
+                "invalid archive entry {}: {}",
+                escape_name(path.as_os_str()),
+                reason
+            ),
+            MergeTreeError::InvalidImage { path, reason } => write!(
+                f,
+                "invalid image {}: {}",
+                escape_name(path.as_os_str()),
+                reason
+            ),
+            MergeTreeError::DigestMismatch {
+                path,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "digest mismatch of {}: expected {}, got {}",
+                escape_name(path.as_os_str()),
+                expected,
+                actual
+            ),
+            MergeTreeError::OrphanWhiteout { paths } => {
+                let paths: Vec<String> = paths
+                    .iter()
+                    .map(|path| escape_name(path.as_os_str()))
+                    .collect();
+                write!(
+                    f,
+                    "orphan whiteout {}, nothing to remove in lower layers",
+                    paths.join(", ")
+                )
+            }
+        }
+    }
+}
+
+impl Error for MergeTreeError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            MergeTreeError::Io { source, .. } | MergeTreeError::Xattr { source, .. } => {
+                Some(source)
+            }
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,916 @@
+use crate::archive::{self, Compression, LayerDigests};
+use crate::error::{MergeTreeError, Result};
+use crate::tree::{FileSystemTree, Overlay, ScanOption, WhiteoutSpec};
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, Read, Seek, SeekFrom};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Component, Path, PathBuf};
+use std::str::FromStr;
+
+/// Annotation holding the tag of a manifest in index.json
+pub const ANNOTATION_REF_NAME: &str = "org.opencontainers.image.ref.name";
+
+const OCI_LAYOUT_FILE: &str = "oci-layout";
+const OCI_LAYOUT_VERSION: &str = "1.0.0";
+const INDEX_FILE: &str = "index.json";
+const DOCKER_MANIFEST_FILE: &str = "manifest.json";
+
+const MEDIA_TYPE_INDEX: &str = "application/vnd.oci.image.index.v1+json";
+const MEDIA_TYPE_MANIFEST: &str = "application/vnd.oci.image.manifest.v1+json";
+const MEDIA_TYPE_DOCKER_LIST: &str = "application/vnd.docker.distribution.manifest.list.v2+json";
+const MEDIA_TYPE_DOCKER_MANIFEST: &str = "application/vnd.docker.distribution.manifest.v2+json";
+
+/// Platform of an image, like "linux/arm64/v8"
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Eq)]
+pub struct Platform {
+    pub os: String,
+    pub architecture: String,
+    #[serde(default)]
+    pub variant: Option<String>,
+}
+
+impl Platform {
+    /// Whether a platform of a manifest is selected by self, variant is only
+    /// checked if it is given
+    pub fn matches(&self, other: &Platform) -> bool {
+        self.os == other.os
+            && self.architecture == other.architecture
+            && (self.variant.is_none() || self.variant == other.variant)
+    }
+}
+
+impl FromStr for Platform {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let parts: Vec<&str> = s.split('/').collect();
+        match parts.as_slice() {
+            [os, architecture] | [os, architecture, _]
+                if !os.is_empty() && !architecture.is_empty() =>
+            {
+                Ok(Platform {
+                    os: os.to_string(),
+                    architecture: architecture.to_string(),
+                    variant: parts.get(2).map(|variant| variant.to_string()),
+                })
+            }
+            _ => Err(format!("platform {} is not os/arch[/variant]", s)),
+        }
+    }
+}
+
+impl fmt::Display for Platform {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.os, self.architecture)?;
+        if let Some(variant) = &self.variant {
+            write!(f, "/{}", variant)?;
+        }
+        Ok(())
+    }
+}
+
+/// Reference to a blob, see the OCI image spec descriptor
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Descriptor {
+    pub media_type: String,
+    /// Digest of the blob, like "sha256:<hex>"
+    pub digest: String,
+    #[allow(dead_code)]
+    pub size: u64,
+    #[serde(default)]
+    pub annotations: HashMap<String, String>,
+    #[serde(default)]
+    pub platform: Option<Platform>,
+}
+
+impl Descriptor {
+    /// Tag of a manifest in index.json
+    pub fn ref_name(&self) -> Option<&str> {
+        self.annotations
+            .get(ANNOTATION_REF_NAME)
+            .map(|s| s.as_str())
+    }
+
+    fn is_index(&self) -> bool {
+        self.media_type == MEDIA_TYPE_INDEX || self.media_type == MEDIA_TYPE_DOCKER_LIST
+    }
+}
+
+/// Image index, both index.json and nested indexes of multi-platform images
+#[derive(Clone, Debug, Deserialize)]
+pub struct Index {
+    pub manifests: Vec<Descriptor>,
+}
+
+/// Image manifest, layers are from base to top
+#[derive(Clone, Debug, Deserialize)]
+pub struct Manifest {
+    pub config: Descriptor,
+    pub layers: Vec<Descriptor>,
+}
+
+/// Image config, only the layers part of it is read
+#[derive(Clone, Debug, Deserialize)]
+pub struct ImageConfig {
+    pub rootfs: RootFs,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct RootFs {
+    /// Digests of uncompressed layers, from base to top
+    pub diff_ids: Vec<String>,
+}
+
+impl ImageConfig {
+    // diff_id of every layer, the config must have one for every layer
+    fn diff_ids(self, layers: usize, path: &Path) -> Result<Vec<String>> {
+        if self.rootfs.diff_ids.len() != layers {
+            return Err(MergeTreeError::InvalidImage {
+                path: path.to_path_buf(),
+                reason: format!(
+                    "config has {} diff_ids for {} layers",
+                    self.rootfs.diff_ids.len(),
+                    layers
+                ),
+            });
+        }
+        Ok(self.rootfs.diff_ids)
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct OciLayout {
+    image_layout_version: String,
+}
+
+/// OCI image layout dir with oci-layout, index.json and blobs
+pub struct ImageLayout {
+    pub path: PathBuf,
+}
+
+impl ImageLayout {
+    /// Open a layout dir, its oci-layout file must have a known version
+    pub fn open(path: PathBuf) -> Result<Self> {
+        let layout = ImageLayout { path };
+        let oci_layout: OciLayout = layout.read_json(&layout.path.join(OCI_LAYOUT_FILE))?;
+        if oci_layout.image_layout_version != OCI_LAYOUT_VERSION {
+            return Err(layout.invalid(format!(
+                "unsupported image layout version {}",
+                oci_layout.image_layout_version
+            )));
+        }
+        Ok(layout)
+    }
+
+    /// Path of a blob by its digest under blobs
+    pub fn blob_path(&self, digest: &str) -> Result<PathBuf> {
+        // digest is a path component, never let it go out of blobs
+        match digest.split_once(':') {
+            Some((algorithm, encoded))
+                if !algorithm.is_empty()
+                    && algorithm.bytes().all(|b| b.is_ascii_alphanumeric())
+                    && !encoded.is_empty()
+                    && encoded.bytes().all(|b| b.is_ascii_hexdigit()) =>
+            {
+                Ok(self.path.join("blobs").join(algorithm).join(encoded))
+            }
+            _ => Err(self.invalid(format!("invalid digest {}", digest))),
+        }
+    }
+
+    /// Resolve the manifest of a tag or a digest, the only manifest if
+    /// reference is None. A multi-platform index is resolved by platform,
+    /// which can be left out if only one entry is there.
+    pub fn resolve_manifest(
+        &self,
+        reference: Option<&str>,
+        platform: Option<&Platform>,
+    ) -> Result<Manifest> {
+        let index: Index = self.read_json(&self.path.join(INDEX_FILE))?;
+        let mut candidates: Vec<Descriptor> = index
+            .manifests
+            .into_iter()
+            .filter(|desc| match reference {
+                Some(reference) => desc.digest == reference || desc.ref_name() == Some(reference),
+                None => true,
+            })
+            .collect();
+        loop {
+            // nested indexes are opened until only manifests are left
+            let mut manifests = Vec::new();
+            let mut nested = false;
+            for desc in candidates {
+                if desc.is_index() {
+                    let index: Index = self.read_blob_json(&desc)?;
+                    manifests.extend(index.manifests);
+                    nested = true;
+                } else {
+                    manifests.push(desc);
+                }
+            }
+            candidates = manifests;
+            if !nested {
+                break;
+            }
+        }
+        if let Some(platform) = platform {
+            candidates.retain(|desc| {
+                desc.platform
+                    .as_ref()
+                    .is_some_and(|other| platform.matches(other))
+            });
+        }
+
+        let desc = match candidates.len() {
+            1 => candidates.remove(0),
+            0 => {
+                let mut reason = format!("no manifest of {}", reference.unwrap_or("the image"));
+                if let Some(platform) = platform {
+                    reason.push_str(&format!(" for platform {}", platform));
+                }
+                return Err(self.invalid(reason));
+            }
+            _ => {
+                let platforms: Vec<String> = candidates
+                    .iter()
+                    .map(|desc| match &desc.platform {
+                        Some(platform) => platform.to_string(),
+                        None => desc.digest.clone(),
+                    })
+                    .collect();
+                return Err(self.invalid(format!(
+                    "several manifests match, select one of {} by platform",
+                    platforms.join(", ")
+                )));
+            }
+        };
+        if desc.media_type != MEDIA_TYPE_MANIFEST && desc.media_type != MEDIA_TYPE_DOCKER_MANIFEST {
+            return Err(self.invalid(format!(
+                "unsupported manifest media type {}",
+                desc.media_type
+            )));
+        }
+        self.read_blob_json(&desc)
+    }
+
+    /// Blob paths of every layer of manifest, from base to top
+    pub fn layer_paths(&self, manifest: &Manifest) -> Result<Vec<PathBuf>> {
+        manifest
+            .layers
+            .iter()
+            .map(|layer| {
+                // layers are tar archives, plain or compressed
+                if !layer.media_type.contains(".tar") {
+                    return Err(
+                        self.invalid(format!("unsupported layer media type {}", layer.media_type))
+                    );
+                }
+                self.blob_path(&layer.digest)
+            })
+            .collect()
+    }
+
+    /// Build every layer of manifest into a tree from base to top, the base
+    /// is a lower layer. Every blob is verified by its digest in manifest and
+    /// by its diff_id in config while it is read.
+    pub fn build_layers(
+        &self,
+        manifest: &Manifest,
+        whiteout_spec: WhiteoutSpec,
+        option: &ScanOption,
+    ) -> Result<Vec<FileSystemTree>> {
+        let paths = self.layer_paths(manifest)?;
+        let config: ImageConfig = self.read_blob_json(&manifest.config)?;
+        let diff_ids = config.diff_ids(paths.len(), &self.blob_path(&manifest.config.digest)?)?;
+        let mut trees = Vec::with_capacity(paths.len());
+        for (i, ((path, layer), diff_id)) in paths
+            .into_iter()
+            .zip(&manifest.layers)
+            .zip(diff_ids)
+            .enumerate()
+        {
+            let overlay = if i == 0 {
+                Overlay::Lower
+            } else {
+                Overlay::None
+            };
+            let digests = LayerDigests {
+                blob: Some(layer.digest.clone()),
+                diff_id: Some(diff_id),
+            };
+            let file = File::open(&path).map_err(|e| MergeTreeError::io(&path, e))?;
+            trees.push(FileSystemTree::build_from_archive_with_digests(
+                file,
+                path,
+                &digests,
+                overlay,
+                whiteout_spec,
+                option,
+            )?);
+        }
+        Ok(trees)
+    }
+
+    // read a json blob and verify it by the digest of descriptor
+    fn read_blob_json<T: DeserializeOwned>(&self, desc: &Descriptor) -> Result<T> {
+        let path = self.blob_path(&desc.digest)?;
+        let data = fs::read(&path).map_err(|e| MergeTreeError::io(&path, e))?;
+        archive::verify_data(&path, &desc.digest, &data)?;
+        parse_json(&path, &data)
+    }
+
+    fn read_json<T: DeserializeOwned>(&self, path: &Path) -> Result<T> {
+        let data = fs::read(path).map_err(|e| MergeTreeError::io(path, e))?;
+        parse_json(path, &data)
+    }
+
+    fn invalid(&self, reason: String) -> MergeTreeError {
+        MergeTreeError::InvalidImage {
+            path: self.path.clone(),
+            reason,
+        }
+    }
+}
+
+/// Image in manifest.json of a docker save archive
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct DockerManifest {
+    /// Path of the image config in the archive
+    pub config: String,
+    #[serde(default)]
+    pub repo_tags: Option<Vec<String>>,
+    /// Paths of layer archives in the archive, from base to top
+    pub layers: Vec<String>,
+}
+
+impl DockerManifest {
+    fn tags(&self) -> &[String] {
+        self.repo_tags.as_deref().unwrap_or_default()
+    }
+}
+
+// file of an entry in the outer archive
+struct DockerEntry {
+    position: u64,
+    size: u64,
+    // target of a symlink or hardlink entry, from the archive root
+    link: Option<PathBuf>,
+}
+
+/// Archive written by docker save, with manifest.json, configs and layer
+/// archives. Files in it are read by seeking to them, never extracted.
+pub struct DockerArchive {
+    pub path: PathBuf,
+    entries: HashMap<PathBuf, DockerEntry>,
+}
+
+impl DockerArchive {
+    /// Open an archive and find every file in it, the archive must not be
+    /// compressed to seek in it
+    pub fn open(path: PathBuf) -> Result<Self> {
+        let io_error = |e| MergeTreeError::io(&path, e);
+        let mut file = BufReader::new(File::open(&path).map_err(io_error)?);
+        if Compression::detect(file.fill_buf().map_err(io_error)?) != Compression::None {
+            return Err(MergeTreeError::InvalidImage {
+                path,
+                reason: "compressed docker archive can't be read in place, decompress it first"
+                    .to_string(),
+            });
+        }
+        let mut entries = HashMap::new();
+        let mut outer = tar::Archive::new(file);
+        for entry in outer.entries_with_seek().map_err(io_error)? {
+            let entry = entry.map_err(io_error)?;
+            let name = normalize(Path::new(OsStr::from_bytes(&entry.path_bytes())));
+            let link = entry.link_name_bytes().map(|target| {
+                let target = Path::new(OsStr::from_bytes(&target));
+                match entry.header().entry_type() {
+                    // symlink is relative to its dir, hardlink to the root
+                    tar::EntryType::Symlink => {
+                        normalize(&name.parent().unwrap_or(Path::new("")).join(target))
+                    }
+                    _ => normalize(target),
+                }
+            });
+            entries.insert(
+                name,
+                DockerEntry {
+                    position: entry.raw_file_position(),
+                    size: entry.size(),
+                    link,
+                },
+            );
+        }
+        Ok(DockerArchive { path, entries })
+    }
+
+    /// Resolve the image of a tag in RepoTags or a config digest, the only
+    /// image if reference is None
+    pub fn resolve_manifest(&self, reference: Option<&str>) -> Result<DockerManifest> {
+        let data = self.read_file(Path::new(DOCKER_MANIFEST_FILE))?;
+        let mut manifests: Vec<DockerManifest> =
+            parse_json(&self.path.join(DOCKER_MANIFEST_FILE), &data)?;
+        if let Some(reference) = reference {
+            // config is named by its digest, like "<hex>.json" or
+            // "blobs/sha256/<hex>"
+            let encoded = reference.rsplit(':').next().unwrap_or(reference);
+            manifests.retain(|manifest| {
+                manifest.tags().iter().any(|tag| tag == reference)
+                    || Path::new(&manifest.config)
+                        .file_stem()
+                        .is_some_and(|stem| stem == encoded)
+            });
+        }
+        match manifests.len() {
+            1 => Ok(manifests.remove(0)),
+            0 => Err(self.invalid(format!(
+                "no image of {}",
+                reference.unwrap_or("the archive")
+            ))),
+            _ => {
+                // untagged image is named by its config
+                let tags: Vec<String> = manifests
+                    .iter()
+                    .map(|manifest| match manifest.tags() {
+                        [] => manifest.config.clone(),
+                        tags => tags.join(" "),
+                    })
+                    .collect();
+                Err(self.invalid(format!(
+                    "several images match, select one of {} by tag or digest",
+                    tags.join(", ")
+                )))
+            }
+        }
+    }
+
+    /// Build every layer of the image into a tree from base to top, the base
+    /// is a lower layer. Every layer is verified by its diff_id in config
+    /// while it is read, and blobs named by digest are verified by it.
+    pub fn build_layers(
+        &self,
+        manifest: &DockerManifest,
+        whiteout_spec: WhiteoutSpec,
+        option: &ScanOption,
+    ) -> Result<Vec<FileSystemTree>> {
+        let config_path = self.path.join(&manifest.config);
+        let data = self.read_file(Path::new(&manifest.config))?;
+        if let Some(digest) = digest_of_name(&manifest.config) {
+            archive::verify_data(&config_path, &digest, &data)?;
+        }
+        let config: ImageConfig = parse_json(&config_path, &data)?;
+        let diff_ids = config.diff_ids(manifest.layers.len(), &config_path)?;
+        let mut trees = Vec::with_capacity(manifest.layers.len());
+        for (i, (layer, diff_id)) in manifest.layers.iter().zip(diff_ids).enumerate() {
+            let overlay = if i == 0 {
+                Overlay::Lower
+            } else {
+                Overlay::None
+            };
+            let layer_path = self.path.join(layer);
+            let digests = LayerDigests {
+                blob: digest_of_name(layer),
+                diff_id: Some(diff_id),
+            };
+            let reader = self.open_file(Path::new(layer))?;
+            trees.push(FileSystemTree::build_from_archive_with_digests(
+                reader,
+                layer_path,
+                &digests,
+                overlay,
+                whiteout_spec,
+                option,
+            )?);
+        }
+        Ok(trees)
+    }
+
+    // reader of a file in the archive, following links
+    fn open_file(&self, name: &Path) -> Result<impl Read> {
+        let mut name = normalize(name);
+        // layers shared by images may be links to each other
+        for _ in 0..8 {
+            let entry = match self.entries.get(&name) {
+                Some(entry) => entry,
+                None => break,
+            };
+            if let Some(link) = &entry.link {
+                name = link.clone();
+                continue;
+            }
+            let path = self.path.join(&name);
+            let mut file = File::open(&self.path).map_err(|e| MergeTreeError::io(&path, e))?;
+            file.seek(SeekFrom::Start(entry.position))
+                .map_err(|e| MergeTreeError::io(&path, e))?;
+            return Ok(file.take(entry.size));
+        }
+        Err(self.invalid(format!("no file {} in the archive", name.display())))
+    }
+
+    fn read_file(&self, name: &Path) -> Result<Vec<u8>> {
+        let mut data = Vec::new();
+        self.open_file(name)?
+            .read_to_end(&mut data)
+            .map_err(|e| MergeTreeError::io(&self.path.join(name), e))?;
+        Ok(data)
+    }
+
+    fn invalid(&self, reason: String) -> MergeTreeError {
+        MergeTreeError::InvalidImage {
+            path: self.path.clone(),
+            reason,
+        }
+    }
+}
+
+fn parse_json<T: DeserializeOwned>(path: &Path, data: &[u8]) -> Result<T> {
+    serde_json::from_slice(data).map_err(|e| MergeTreeError::InvalidImage {
+        path: path.to_path_buf(),
+        reason: e.to_string(),
+    })
+}
+
+// digest of a file named by it in docker archive, like "<hex>.json" of old
+// format or "blobs/sha256/<hex>" of OCI format
+fn digest_of_name(name: &str) -> Option<String> {
+    let path = Path::new(name);
+    let encoded = match path.parent() {
+        Some(parent) if parent.ends_with("blobs/sha256") => path.file_name()?,
+        _ => path.file_stem()?,
+    }
+    .to_str()?;
+    if encoded.len() == 64 && encoded.bytes().all(|b| b.is_ascii_hexdigit()) {
+        Some(format!("{}:{}", archive::DIGEST_ALGORITHM, encoded))
+    } else {
+        None
+    }
+}
+
+// path in an archive without "./" and "..", which can't go out of the root
+fn normalize(path: &Path) -> PathBuf {
+    let mut out = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(name) => out.push(name),
+            Component::ParentDir => {
+                out.pop();
+            }
+            _ => {}
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::archive::{decompress, sha256_digest, Compression};
+    use crate::build::BuildTree;
+    use crate::error::MergeTreeError;
+    use crate::image::{DockerArchive, ImageLayout, Platform, ANNOTATION_REF_NAME};
+    use crate::test_util::{
+        tar_entry, tar_example_layer, tar_example_layer_without_root, tar_header, test_dir,
+    };
+    use crate::tree::{FileSystemTree, Overlay, ScanOption, WhiteoutSpec};
+    use std::fs;
+    use std::path::{Path, PathBuf};
+
+    const OCI_MANIFEST: &str = "application/vnd.oci.image.manifest.v1+json";
+
+    // write a blob into an OCI image layout and return its descriptor
+    fn oci_blob(layout: &Path, media_type: &str, data: &[u8]) -> serde_json::Value {
+        let digest = sha256_digest(data);
+        let dir = layout.join("blobs/sha256");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join(&digest["sha256:".len()..]), data).unwrap();
+        serde_json::json!({
+            "mediaType": media_type,
+            "digest": digest,
+            "size": data.len(),
+        })
+    }
+
+    // image config with diff_ids of layer archives, which may be compressed
+    fn image_config(layers: &[Vec<u8>]) -> Vec<u8> {
+        use std::io::Read;
+        let diff_ids: Vec<String> = layers
+            .iter()
+            .map(|layer| {
+                let mut tar = Vec::new();
+                decompress(layer.as_slice())
+                    .unwrap()
+                    .read_to_end(&mut tar)
+                    .unwrap();
+                sha256_digest(&tar)
+            })
+            .collect();
+        serde_json::json!({
+            "architecture": "amd64",
+            "os": "linux",
+            "rootfs": {"type": "layers", "diff_ids": diff_ids},
+        })
+        .to_string()
+        .into_bytes()
+    }
+
+    // write an image of layer archives into layout, return manifest descriptor
+    fn oci_image(layout: &Path, layers: &[Vec<u8>]) -> serde_json::Value {
+        let config = oci_blob(
+            layout,
+            "application/vnd.oci.image.config.v1+json",
+            &image_config(layers),
+        );
+        let layers: Vec<_> = layers
+            .iter()
+            .map(|layer| {
+                let media_type = match Compression::detect(layer) {
+                    Compression::Gzip => "application/vnd.oci.image.layer.v1.tar+gzip",
+                    _ => "application/vnd.oci.image.layer.v1.tar",
+                };
+                oci_blob(layout, media_type, layer)
+            })
+            .collect();
+        let manifest = serde_json::json!({
+            "schemaVersion": 2,
+            "mediaType": OCI_MANIFEST,
+            "config": config,
+            "layers": layers,
+        });
+        oci_blob(layout, OCI_MANIFEST, manifest.to_string().as_bytes())
+    }
+
+    // write oci-layout and index.json of manifests
+    fn write_oci_index(layout: &Path, manifests: Vec<serde_json::Value>) {
+        fs::write(
+            layout.join("oci-layout"),
+            r#"{"imageLayoutVersion": "1.0.0"}"#,
+        )
+        .unwrap();
+        let index = serde_json::json!({"schemaVersion": 2, "manifests": manifests});
+        fs::write(layout.join("index.json"), index.to_string()).unwrap();
+    }
+
+    // merge layers from base to top like the CLI
+    fn merge_layer_paths(mut paths: Vec<PathBuf>) -> String {
+        let base = paths.remove(0);
+        let base_tree = FileSystemTree::build_layer(
+            base,
+            Overlay::Lower,
+            WhiteoutSpec::Oci,
+            &ScanOption::default(),
+        )
+        .unwrap();
+        let upper_trees = FileSystemTree::build_layers(
+            paths,
+            Overlay::None,
+            WhiteoutSpec::Oci,
+            &ScanOption::default(),
+        )
+        .unwrap();
+        let mut trees = vec![base_tree];
+        trees.extend(upper_trees);
+        merge_layer_trees(trees).base_tree.format_file_tree()
+    }
+
+    // merge trees of layers from base to top
+    fn merge_layer_trees(mut trees: Vec<FileSystemTree>) -> BuildTree {
+        let mut build = BuildTree::new(trees.remove(0));
+        for upper_tree in trees {
+            build
+                .apply_tree_by_dfs(&upper_tree, WhiteoutSpec::Oci)
+                .unwrap();
+        }
+        build
+    }
+
+    #[test]
+    fn test_oci_image_layout() {
+        use std::io::Write;
+        let dirs: Vec<PathBuf> = ["base-dir", "upper1-dir", "upper2-dir", "upper3-dir"]
+            .iter()
+            .map(|dir| Path::new("./file-example/example6").join(dir))
+            .collect();
+        let mut layers: Vec<Vec<u8>> = dirs.iter().map(|dir| tar_example_layer(dir)).collect();
+        // top layer has no "./" entry, the root is still from the layer below
+        layers[3] = tar_example_layer_without_root(&dirs[3]);
+        let mut gzip = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        gzip.write_all(&layers[2]).unwrap();
+        layers[2] = gzip.finish().unwrap();
+
+        let layout_path = test_dir("oci-layout");
+        let mut v1 = oci_image(&layout_path, &layers);
+        v1["annotations"] = serde_json::json!({ANNOTATION_REF_NAME: "v1"});
+        let mut v0 = oci_image(&layout_path, &layers[..1]);
+        v0["annotations"] = serde_json::json!({ANNOTATION_REF_NAME: "v0"});
+        let v0_digest = v0["digest"].as_str().unwrap().to_string();
+        write_oci_index(&layout_path, vec![v1, v0]);
+
+        let layout = ImageLayout::open(layout_path.clone()).unwrap();
+        let manifest = layout.resolve_manifest(Some("v1"), None).unwrap();
+        let paths = layout.layer_paths(&manifest).unwrap();
+        assert_eq!(paths.len(), 4);
+        assert!(paths[0].starts_with(layout_path.join("blobs/sha256")));
+        let trees = layout
+            .build_layers(&manifest, WhiteoutSpec::Oci, &ScanOption::default())
+            .unwrap();
+        let build = merge_layer_trees(trees);
+        assert_eq!(build.base_tree.format_file_tree(), merge_layer_paths(dirs));
+        let root = build.base_tree.data.root().data();
+        assert_eq!((root.layer, root.shadowed.clone()), (2, vec![0, 1]));
+
+        // a blob replaced by another valid layer is found by its digest
+        fs::copy(&paths[3], &paths[1]).unwrap();
+        let err = layout
+            .build_layers(&manifest, WhiteoutSpec::Oci, &ScanOption::default())
+            .err()
+            .unwrap();
+        assert!(matches!(err, MergeTreeError::DigestMismatch { .. }));
+        assert_eq!(err.exit_code(), 10);
+        assert!(err.to_string().contains(&paths[1].display().to_string()));
+
+        let manifest = layout.resolve_manifest(Some(&v0_digest), None).unwrap();
+        assert_eq!(manifest.layers.len(), 1);
+
+        // tag is required if there are several images
+        let err = layout.resolve_manifest(None, None).err().unwrap();
+        assert!(matches!(err, MergeTreeError::InvalidImage { .. }));
+        assert_eq!(err.exit_code(), 9);
+        let err = layout.resolve_manifest(Some("v2"), None).err().unwrap();
+        assert!(err.to_string().contains("no manifest of v2"));
+        // digest never goes out of blobs
+        assert!(layout.blob_path("sha256:../../etc/passwd").is_err());
+
+        let err = ImageLayout::open(PathBuf::from("./file-example/example1"))
+            .err()
+            .unwrap();
+        assert!(matches!(err, MergeTreeError::Io { .. }));
+    }
+
+    #[test]
+    fn test_oci_image_platform() {
+        let base = tar_example_layer(Path::new("./file-example/example6/base-dir"));
+        let upper = tar_example_layer(Path::new("./file-example/example6/upper1-dir"));
+        let layout_path = test_dir("oci-platform");
+        let mut arm64 = oci_image(&layout_path, &[base.clone(), upper]);
+        arm64["platform"] =
+            serde_json::json!({"os": "linux", "architecture": "arm64", "variant": "v8"});
+        let mut amd64 = oci_image(&layout_path, &[base]);
+        amd64["platform"] = serde_json::json!({"os": "linux", "architecture": "amd64"});
+        // multi-platform image is a nested index
+        let index = serde_json::json!({"schemaVersion": 2, "manifests": [amd64, arm64]});
+        let mut index = oci_blob(
+            &layout_path,
+            "application/vnd.oci.image.index.v1+json",
+            index.to_string().as_bytes(),
+        );
+        index["annotations"] = serde_json::json!({ANNOTATION_REF_NAME: "latest"});
+        write_oci_index(&layout_path, vec![index]);
+
+        let layout = ImageLayout::open(layout_path).unwrap();
+        let err = layout.resolve_manifest(Some("latest"), None).err().unwrap();
+        assert!(err.to_string().contains("linux/amd64, linux/arm64/v8"));
+
+        let platform: Platform = "linux/arm64".parse().unwrap();
+        let manifest = layout.resolve_manifest(None, Some(&platform)).unwrap();
+        assert_eq!(manifest.layers.len(), 2);
+        let platform: Platform = "linux/amd64".parse().unwrap();
+        let manifest = layout
+            .resolve_manifest(Some("latest"), Some(&platform))
+            .unwrap();
+        assert_eq!(manifest.layers.len(), 1);
+
+        let platform: Platform = "linux/arm64/v7".parse().unwrap();
+        let err = layout
+            .resolve_manifest(None, Some(&platform))
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("for platform linux/arm64/v7"));
+        assert!("linux".parse::<Platform>().is_err());
+    }
+
+    // write an archive like docker save of example6 with two images, the
+    // last layer is shared as a link like docker does and has no root entry
+    fn gen_docker_archive(name: &str) -> PathBuf {
+        let path = test_dir(name).join("image.tar");
+        let mut builder = tar::Builder::new(Vec::new());
+        let mut layer_data: Vec<Vec<u8>> = ["base-dir", "upper1-dir", "upper2-dir", "upper3-dir"]
+            .iter()
+            .map(|dir| tar_example_layer(&Path::new("./file-example/example6").join(dir)))
+            .collect();
+        layer_data[3] =
+            tar_example_layer_without_root(Path::new("./file-example/example6/upper3-dir"));
+        let mut layers = Vec::new();
+        for (i, layer) in layer_data[..3].iter().enumerate() {
+            let layer_path = format!("layer{}/layer.tar", i);
+            tar_entry(
+                &mut builder,
+                &format!("layer{}/", i),
+                tar::EntryType::Directory,
+                b"",
+            );
+            tar_entry(&mut builder, &layer_path, tar::EntryType::Regular, layer);
+            layers.push(layer_path);
+        }
+        tar_entry(
+            &mut builder,
+            "shared/layer.tar",
+            tar::EntryType::Regular,
+            &layer_data[3],
+        );
+        let mut link = tar_header(tar::EntryType::Symlink);
+        builder
+            .append_link(&mut link, "layer3/layer.tar", "../shared/layer.tar")
+            .unwrap();
+        layers.push("layer3/layer.tar".to_string());
+        // config is named by its digest, the broken one has wrong diff_ids
+        let config_data = image_config(&layer_data);
+        let config = format!("{}.json", &sha256_digest(&config_data)["sha256:".len()..]);
+        tar_entry(&mut builder, &config, tar::EntryType::Regular, &config_data);
+        let broken_data = image_config(&[
+            layer_data[0].clone(),
+            layer_data[2].clone(),
+            layer_data[1].clone(),
+            layer_data[3].clone(),
+        ]);
+        tar_entry(
+            &mut builder,
+            "broken.json",
+            tar::EntryType::Regular,
+            &broken_data,
+        );
+        let manifest = serde_json::json!([
+            {"Config": config, "RepoTags": ["example6:latest"], "Layers": layers},
+            {"Config": "base.json", "RepoTags": null, "Layers": ["layer0/layer.tar"]},
+            {"Config": "broken.json", "RepoTags": ["example6:broken"], "Layers": layers},
+        ]);
+        tar_entry(
+            &mut builder,
+            "manifest.json",
+            tar::EntryType::Regular,
+            manifest.to_string().as_bytes(),
+        );
+        fs::write(&path, builder.into_inner().unwrap()).unwrap();
+        path
+    }
+
+    #[test]
+    fn test_docker_archive() {
+        let path = gen_docker_archive("docker-archive");
+        let archive = DockerArchive::open(path.clone()).unwrap();
+        let manifest = archive.resolve_manifest(Some("example6:latest")).unwrap();
+        let trees = archive
+            .build_layers(&manifest, WhiteoutSpec::Oci, &ScanOption::default())
+            .unwrap();
+        assert_eq!(trees.len(), 4);
+        assert_eq!(
+            trees[1].layer_path(0),
+            Some(path.join("layer1/layer.tar").as_path())
+        );
+        let dirs: Vec<PathBuf> = ["base-dir", "upper1-dir", "upper2-dir", "upper3-dir"]
+            .iter()
+            .map(|dir| Path::new("./file-example/example6").join(dir))
+            .collect();
+        let build = merge_layer_trees(trees);
+        assert_eq!(build.base_tree.format_file_tree(), merge_layer_paths(dirs));
+        let root = build.base_tree.data.root().data();
+        assert_eq!((root.layer, root.shadowed.clone()), (2, vec![0, 1]));
+
+        // image by config digest
+        let digest = format!("sha256:{}", manifest.config.trim_end_matches(".json"));
+        let manifest = archive.resolve_manifest(Some(&digest)).unwrap();
+        assert_eq!(manifest.layers.len(), 4);
+        let err = archive.resolve_manifest(None).err().unwrap();
+        assert!(err
+            .to_string()
+            .contains("example6:latest, base.json, example6:broken"));
+
+        // layers out of the order of diff_ids
+        let manifest = archive.resolve_manifest(Some("example6:broken")).unwrap();
+        let err = archive
+            .build_layers(&manifest, WhiteoutSpec::Oci, &ScanOption::default())
+            .err()
+            .unwrap();
+        assert!(matches!(err, MergeTreeError::DigestMismatch { .. }));
+        assert!(err.to_string().contains("image.tar/layer1/layer.tar"));
+        let err = archive.resolve_manifest(Some("busybox")).err().unwrap();
+        assert!(matches!(err, MergeTreeError::InvalidImage { .. }));
+
+        // outer archive can't be seeked in if it is compressed
+        let gzip_path = path.with_extension("tar.gz");
+        let mut gzip = flate2::write::GzEncoder::new(
+            fs::File::create(&gzip_path).unwrap(),
+            flate2::Compression::default(),
+        );
+        std::io::copy(&mut fs::File::open(&path).unwrap(), &mut gzip).unwrap();
+        gzip.finish().unwrap();
+        let err = DockerArchive::open(gzip_path).err().unwrap();
+        assert!(matches!(err, MergeTreeError::InvalidImage { .. }));
+    }
+}
@@ -1,11 +1,24 @@
+mod archive;
 mod build;
+mod error;
+mod image;
+mod meta;
 mod option;
+mod scan;
+#[cfg(test)]
+mod test_util;
 mod tree;
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use std::path::Path;
 use structopt::StructOpt;
 
-use crate::build::BuildTree;
+use crate::build::{BuildTree, OrphanWhiteout};
+use crate::error::{MergeTreeError, Result};
+use crate::image::{DockerArchive, ImageLayout};
 use crate::option::MergeTreeOpt;
-use crate::tree::{FileSystemTree, Overlay, WhiteoutSpec};
+use crate::tree::{
+    ChildOrder, DisplayOption, FileSystemTree, Overlay, ScanOption, WhiteoutSpec, XattrOption,
+};
 
 ///               basedir
 ///                 /
@@ -21,31 +34,134 @@ use crate::tree::{FileSystemTree, Overlay, WhiteoutSpec};
 ///
 ///              mergedir
 /// /a
-
 fn main() {
     let opt = MergeTreeOpt::from_args();
+    if log::set_logger(&LOGGER).is_ok() {
+        log::set_max_level(LevelFilter::Warn);
+    }
     let mut whiteout_spec = WhiteoutSpec::Oci;
     if opt.whiteout == 1 {
         whiteout_spec = WhiteoutSpec::Overlayfs
     }
 
-    // 1. build base tree
-    let base_tree =
-        FileSystemTree::build_from_file_system(opt.base_path, Overlay::Lower, whiteout_spec)
-            .unwrap();
+    let mut scan_option = ScanOption::default();
+    if opt.insertion_order {
+        scan_option.child_order = ChildOrder::Insertion;
+    }
+    scan_option.threads = opt.threads.unwrap_or_else(|| {
+        std::thread::available_parallelism()
+            .map(|n| n.get())
+            .unwrap_or(1)
+    });
+    scan_option.max_depth = opt.max_depth;
+    scan_option.one_file_system = opt.one_file_system;
+    scan_option.xattrs = XattrOption {
+        enabled: !opt.no_xattrs,
+        include: opt.xattr_include.clone(),
+        exclude: opt.xattr_exclude.clone(),
+    };
+
+    // 1. build base tree and upper trees, upper dirs are scanned all together
+    let mut upper_trees =
+        build_layer_trees(&opt, whiteout_spec, &scan_option).unwrap_or_else(|e| exit_with_error(e));
+    let base_tree = upper_trees.remove(0);
 
     // 2. create tree build
     let mut build = BuildTree::new(base_tree);
+    if opt.strict_whiteout {
+        build.orphan_whiteout = OrphanWhiteout::Strict;
+    }
 
-    // 3. build upper tree by upper path and then to merge
-    for upper_path in opt.upper_path_list {
-        let upper_tree =
-            FileSystemTree::build_from_file_system(upper_path, Overlay::None, whiteout_spec)
-                .unwrap();
-
-        build.apply_tree_by_dfs(upper_tree.data.root(), 0, whiteout_spec);
+    // 3. merge upper trees in order
+    for upper_tree in &upper_trees {
+        if let Err(e) = build.apply_tree_by_dfs(upper_tree, whiteout_spec) {
+            let upper_path = upper_tree.layer_path(0).unwrap_or(Path::new(""));
+            eprintln!("failed to merge {}", upper_path.display());
+            exit_with_error(e);
+        }
+    }
+    for diagnostic in &build.diagnostics {
+        eprintln!("warning: {}", diagnostic);
     }
 
     // 4. display merge tree
-    build.display_base_tree()
+    let display_option = DisplayOption {
+        show_layer: opt.show_layer,
+    };
+    build.display_base_tree_with_option(&display_option)
+}
+
+// trees of every layer from base to top, the layers of the image selected by
+// --image-ref and --platform or --base-path, then --upper-path on top of them
+fn build_layer_trees(
+    opt: &MergeTreeOpt,
+    whiteout_spec: WhiteoutSpec,
+    option: &ScanOption,
+) -> Result<Vec<FileSystemTree>> {
+    let mut trees = match &opt.image {
+        // docker save archive is read in place
+        Some(image) if image.is_file() => {
+            if opt.platform.is_some() {
+                log::warn!("--platform is ignored for docker save archive");
+            }
+            let archive = DockerArchive::open(image.clone())?;
+            let manifest = archive.resolve_manifest(opt.image_ref.as_deref())?;
+            archive.build_layers(&manifest, whiteout_spec, option)?
+        }
+        Some(image) => {
+            let layout = ImageLayout::open(image.clone())?;
+            let manifest =
+                layout.resolve_manifest(opt.image_ref.as_deref(), opt.platform.as_ref())?;
+            layout.build_layers(&manifest, whiteout_spec, option)?
+        }
+        None => vec![FileSystemTree::build_layer(
+            opt.base_path.clone().unwrap(),
+            Overlay::Lower,
+            whiteout_spec,
+            option,
+        )?],
+    };
+    if trees.is_empty() {
+        return Err(MergeTreeError::InvalidImage {
+            path: opt.image.clone().unwrap_or_default(),
+            reason: "image has no layers".to_string(),
+        });
+    }
+    trees.extend(FileSystemTree::build_layers(
+        opt.upper_path_list.clone(),
+        Overlay::None,
+        whiteout_spec,
+        option,
+    )?);
+    Ok(trees)
+}
+
+// print warnings like skipped xattrs to stderr
+struct StderrLogger;
+
+static LOGGER: StderrLogger = StderrLogger;
+
+impl Log for StderrLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            // same prefix as merge diagnostics
+            let level = match record.level() {
+                Level::Warn => "warning".to_string(),
+                level => level.as_str().to_lowercase(),
+            };
+            eprintln!("{}: {}", level, record.args());
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+// print readable error and exit with the code of its kind
+fn exit_with_error(e: MergeTreeError) -> ! {
+    eprintln!("error: {}", e);
+    std::process::exit(e.exit_code());
 }
@@ -0,0 +1,146 @@
+use nix::sys::stat;
+use serde::{Deserialize, Serialize};
+use std::fs::Metadata;
+use std::os::unix::fs::MetadataExt;
+
+/// Type of file, the S_IFMT bits of st_mode
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum FileType {
+    Directory,
+    Regular,
+    Symlink,
+    CharDevice,
+    BlockDevice,
+    Fifo,
+    Socket,
+}
+
+impl FileType {
+    /// Parse the file type bits of a full st_mode
+    pub fn from_mode(mode: u32) -> Option<Self> {
+        match mode & libc::S_IFMT {
+            libc::S_IFDIR => Some(FileType::Directory),
+            libc::S_IFREG => Some(FileType::Regular),
+            libc::S_IFLNK => Some(FileType::Symlink),
+            libc::S_IFCHR => Some(FileType::CharDevice),
+            libc::S_IFBLK => Some(FileType::BlockDevice),
+            libc::S_IFIFO => Some(FileType::Fifo),
+            libc::S_IFSOCK => Some(FileType::Socket),
+            _ => None,
+        }
+    }
+
+    /// File type bits to build a full st_mode
+    #[allow(dead_code)]
+    pub fn mode_bits(self) -> u32 {
+        match self {
+            FileType::Directory => libc::S_IFDIR,
+            FileType::Regular => libc::S_IFREG,
+            FileType::Symlink => libc::S_IFLNK,
+            FileType::CharDevice => libc::S_IFCHR,
+            FileType::BlockDevice => libc::S_IFBLK,
+            FileType::Fifo => libc::S_IFIFO,
+            FileType::Socket => libc::S_IFSOCK,
+        }
+    }
+}
+
+/// Owned metadata of a node, it can be read from a file, or built from
+/// other sources like archives and tests, and edited during merge. It can be
+/// serialized to keep a tree out of the file system.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NodeMeta {
+    pub file_type: FileType,
+    /// Permission bits with setuid, setgid and sticky, without file type
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub size: u64,
+    /// Device number of a char or block device
+    pub rdev: u64,
+    pub nlink: u64,
+    /// Device and inode the file is read from, 0 if it is not read from a
+    /// file system
+    pub dev: u64,
+    pub ino: u64,
+    pub atime: i64,
+    pub atime_nsec: i64,
+    pub mtime: i64,
+    pub mtime_nsec: i64,
+    pub ctime: i64,
+    pub ctime_nsec: i64,
+}
+
+impl NodeMeta {
+    /// Metadata with the given type and permission, owned by root, and every
+    /// other field zero except nlink
+    #[allow(dead_code)]
+    pub fn new(file_type: FileType, mode: u32) -> Self {
+        NodeMeta {
+            file_type,
+            mode: mode & 0o7777,
+            uid: 0,
+            gid: 0,
+            size: 0,
+            rdev: 0,
+            nlink: 1,
+            dev: 0,
+            ino: 0,
+            atime: 0,
+            atime_nsec: 0,
+            mtime: 0,
+            mtime_nsec: 0,
+            ctime: 0,
+            ctime_nsec: 0,
+        }
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.file_type == FileType::Directory
+    }
+
+    pub fn is_file(&self) -> bool {
+        self.file_type == FileType::Regular
+    }
+
+    pub fn is_symlink(&self) -> bool {
+        self.file_type == FileType::Symlink
+    }
+
+    /// Full st_mode with file type bits
+    #[allow(dead_code)]
+    pub fn st_mode(&self) -> u32 {
+        self.file_type.mode_bits() | self.mode
+    }
+
+    pub fn major(&self) -> u64 {
+        stat::major(self.rdev)
+    }
+
+    pub fn minor(&self) -> u64 {
+        stat::minor(self.rdev)
+    }
+}
+
+impl From<&Metadata> for NodeMeta {
+    fn from(meta: &Metadata) -> Self {
+        NodeMeta {
+            // st_mode of a file from Linux always has a known type
+            file_type: FileType::from_mode(meta.mode()).unwrap_or(FileType::Regular),
+            mode: meta.mode() & 0o7777,
+            uid: meta.uid(),
+            gid: meta.gid(),
+            size: meta.size(),
+            rdev: meta.rdev(),
+            nlink: meta.nlink(),
+            dev: meta.dev(),
+            ino: meta.ino(),
+            atime: meta.atime(),
+            atime_nsec: meta.atime_nsec(),
+            mtime: meta.mtime(),
+            mtime_nsec: meta.mtime_nsec(),
+            ctime: meta.ctime(),
+            ctime_nsec: meta.ctime_nsec(),
+        }
+    }
+}
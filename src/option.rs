@@ -1,18 +1,72 @@
+use crate::image::Platform;
 use std::path::PathBuf;
 use structopt::StructOpt;
 
 #[derive(Debug, StructOpt)]
 pub struct MergeTreeOpt {
-    /// Base dir path
-    #[structopt(short = "b", long = "base-path", required = true)]
-    pub base_path: PathBuf,
+    /// Base dir or tar archive path
+    #[structopt(
+        short = "b",
+        long = "base-path",
+        required_unless = "image",
+        conflicts_with = "image"
+    )]
+    pub base_path: Option<PathBuf>,
 
-    /// Upper dir path list, can multiply
-    #[structopt(short = "u", long = "upper-path", required = true)]
+    /// Upper dir or tar archive path list, can multiply, applied on top of image layers with --image
+    #[structopt(short = "u", long = "upper-path")]
     pub upper_path_list: Vec<PathBuf>,
 
+    /// OCI image layout dir or docker save archive to merge all layers of an image from
+    #[structopt(long = "image")]
+    pub image: Option<PathBuf>,
+
+    /// Tag or digest of the image in --image, can be left out if there is only one
+    #[structopt(long = "image-ref", requires = "image")]
+    pub image_ref: Option<String>,
+
+    /// Platform of a multi-platform image in OCI image layout, like linux/arm64/v8
+    #[structopt(long = "platform", requires = "image")]
+    pub platform: Option<Platform>,
+
     /// Whiteout type
     // 0 is OCI, 1 is Overlayfs, default is 0
     #[structopt(short = "w", long = "whiteout-type", default_value = "0")]
     pub whiteout: u32,
+
+    /// Fail if a whiteout removes nothing in lower layers, default only warns
+    #[structopt(long = "strict-whiteout")]
+    pub strict_whiteout: bool,
+
+    /// Keep children in the order they are read in, default sorts them by name
+    #[structopt(long = "insertion-order")]
+    pub insertion_order: bool,
+
+    /// Show which layer every entry comes from and which layers it shadows
+    #[structopt(long = "show-layer")]
+    pub show_layer: bool,
+
+    /// Number of threads to scan layers, default is the number of CPUs
+    #[structopt(short = "j", long = "threads")]
+    pub threads: Option<usize>,
+
+    /// Fail if an entry of a layer is nested deeper than it
+    #[structopt(long = "max-depth")]
+    pub max_depth: Option<usize>,
+
+    /// Don't scan into other file systems mounted in a layer, keep mount points only
+    #[structopt(short = "x", long = "one-file-system")]
+    pub one_file_system: bool,
+
+    /// Don't read xattrs of layers
+    #[structopt(long = "no-xattrs")]
+    pub no_xattrs: bool,
+
+    /// Only keep xattrs with the key prefix, can multiply, e.g. security.capability
+    #[structopt(long = "xattr-include")]
+    pub xattr_include: Vec<String>,
+
+    /// Drop xattrs with the key prefix, can multiply, e.g. security.selinux
+    #[structopt(long = "xattr-exclude")]
+    pub xattr_exclude: Vec<String>,
 }
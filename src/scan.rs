@@ -0,0 +1,237 @@
+use crate::error::{MergeTreeError, Result};
+use crate::tree::{FileSystemTree, Overlay, ScanOption, TreeNode, WhiteoutSpec};
+use std::collections::{HashMap, VecDeque};
+use std::path::PathBuf;
+use std::sync::{Condvar, Mutex};
+use std::thread;
+
+/// Children of every scanned dir by dir id, in the order of ScanOption. A
+/// child dir comes with the id its own children are stored with.
+///
+/// Tree can't be sent between threads, so workers only produce nodes and the
+/// tree is assembled on the caller thread.
+pub type ScannedDirs = HashMap<usize, Vec<(TreeNode, Option<usize>)>>;
+
+// a dir to scan
+struct ScanJob {
+    id: usize,
+    path: PathBuf,
+    // depth of the dir, layer root is 0
+    depth: usize,
+    // device of the layer root, see ScanOption::one_file_system
+    root_dev: u64,
+}
+
+struct PoolState {
+    // dirs waiting to be scanned
+    queue: VecDeque<ScanJob>,
+    // dirs being scanned by workers, their subdirs are not queued yet
+    running: usize,
+    next_id: usize,
+    dirs: ScannedDirs,
+    error: Option<MergeTreeError>,
+}
+
+/// Scan root dirs with their devices, with ids 0..roots.len(), and every dir
+/// under them by a pool of ScanOption::threads workers. The first error stops
+/// the scan.
+pub fn scan_dirs(
+    roots: Vec<(PathBuf, u64)>,
+    overlay: Overlay,
+    whiteout_spec: WhiteoutSpec,
+    option: &ScanOption,
+) -> Result<ScannedDirs> {
+    let state = PoolState {
+        next_id: roots.len(),
+        queue: roots
+            .into_iter()
+            .enumerate()
+            .map(|(id, (path, root_dev))| ScanJob {
+                id,
+                path,
+                depth: 0,
+                root_dev,
+            })
+            .collect(),
+        running: 0,
+        dirs: HashMap::new(),
+        error: None,
+    };
+    let pool = (Mutex::new(state), Condvar::new());
+    let worker = || scan_worker(&pool, overlay, whiteout_spec, option);
+    if option.threads <= 1 {
+        worker();
+    } else {
+        thread::scope(|scope| {
+            for _ in 0..option.threads {
+                scope.spawn(worker);
+            }
+        });
+    }
+
+    let state = pool.0.into_inner().unwrap();
+    match state.error {
+        Some(e) => Err(e),
+        None => Ok(state.dirs),
+    }
+}
+
+fn scan_worker(
+    pool: &(Mutex<PoolState>, Condvar),
+    overlay: Overlay,
+    whiteout_spec: WhiteoutSpec,
+    option: &ScanOption,
+) {
+    let (lock, cvar) = pool;
+    loop {
+        let job = {
+            let mut state = lock.lock().unwrap();
+            // wait for subdirs of running dirs if nothing is queued
+            while state.queue.is_empty() && state.running > 0 && state.error.is_none() {
+                state = cvar.wait(state).unwrap();
+            }
+            if state.error.is_some() {
+                return;
+            }
+            match state.queue.pop_front() {
+                Some(job) => {
+                    state.running += 1;
+                    job
+                }
+                // nothing queued or running, all done
+                None => return,
+            }
+        };
+
+        let result =
+            FileSystemTree::scan_dir(&job.path, job.root_dev, overlay, whiteout_spec, option)
+                .and_then(|nodes| match (option.max_depth, nodes.first()) {
+                    (Some(max_depth), Some(node)) if job.depth >= max_depth => {
+                        Err(MergeTreeError::TooDeep {
+                            path: node.source.clone(),
+                            max_depth,
+                        })
+                    }
+                    _ => Ok(nodes),
+                });
+
+        let mut state = lock.lock().unwrap();
+        state.running -= 1;
+        match result {
+            Ok(nodes) => {
+                let mut children = Vec::with_capacity(nodes.len());
+                for node in nodes {
+                    // mount point is a boundary, never scanned into
+                    let child_id = if node.is_directory() && !node.mount_point {
+                        let child_id = state.next_id;
+                        state.next_id += 1;
+                        state.queue.push_back(ScanJob {
+                            id: child_id,
+                            path: node.source.clone(),
+                            depth: job.depth + 1,
+                            root_dev: job.root_dev,
+                        });
+                        Some(child_id)
+                    } else {
+                        None
+                    };
+                    children.push((node, child_id));
+                }
+                state.dirs.insert(job.id, children);
+            }
+            Err(e) => {
+                if state.error.is_none() {
+                    state.error = Some(e);
+                }
+            }
+        }
+        cvar.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::MergeTreeError;
+    use crate::test_util::{test_dir, tree_paths};
+    use crate::tree::{FileSystemTree, Overlay, ScanOption, WhiteoutSpec};
+    use std::fs;
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_parallel_scan() {
+        let path = test_dir("parallel-scan");
+        for i in 0..8 {
+            for j in 0..8 {
+                let dir = path.join(format!("d{}", i)).join(format!("d{}", j));
+                fs::create_dir_all(&dir).unwrap();
+                for k in 0..4 {
+                    fs::write(dir.join(format!("file{}", k)), b"").unwrap();
+                }
+            }
+        }
+        let serial =
+            FileSystemTree::build_from_file_system(path.clone(), Overlay::Lower, WhiteoutSpec::Oci)
+                .unwrap();
+        let option = ScanOption {
+            threads: 8,
+            ..ScanOption::default()
+        };
+        let parallel = FileSystemTree::build_from_file_system_with_option(
+            path,
+            Overlay::Lower,
+            WhiteoutSpec::Oci,
+            &option,
+        )
+        .unwrap();
+        assert_eq!(tree_paths(&parallel).len(), 8 + 8 * 8 + 8 * 8 * 4);
+        assert_eq!(parallel.format_file_tree(), serial.format_file_tree());
+    }
+
+    #[test]
+    fn test_scan_layers_together() {
+        let paths: Vec<PathBuf> = [
+            "example6/base-dir",
+            "example6/upper1-dir",
+            "example6/upper2-dir",
+        ]
+        .iter()
+        .map(|path| PathBuf::from("./file-example").join(path))
+        .collect();
+        let option = ScanOption {
+            threads: 4,
+            ..ScanOption::default()
+        };
+        let trees = FileSystemTree::build_layers_from_file_system(
+            paths.clone(),
+            Overlay::None,
+            WhiteoutSpec::Oci,
+            &option,
+        )
+        .unwrap();
+        assert_eq!(trees.len(), paths.len());
+        for (tree, path) in trees.iter().zip(paths) {
+            let alone = FileSystemTree::build_from_file_system(
+                path.clone(),
+                Overlay::None,
+                WhiteoutSpec::Oci,
+            )
+            .unwrap();
+            assert_eq!(tree.layers, vec![path]);
+            assert_eq!(tree.format_file_tree(), alone.format_file_tree());
+        }
+
+        // any missing layer fails the whole scan
+        let err = FileSystemTree::build_layers_from_file_system(
+            vec![
+                PathBuf::from("./file-example/example6/base-dir"),
+                PathBuf::from("./file-example/not-exist"),
+            ],
+            Overlay::None,
+            WhiteoutSpec::Oci,
+            &option,
+        )
+        .err()
+        .unwrap();
+        assert!(matches!(err, MergeTreeError::Io { .. }));
+    }
+}
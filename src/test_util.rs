@@ -0,0 +1,113 @@
+use crate::meta::NodeMeta;
+use crate::tree::{escape_name, FileSystemTree, Overlay, TreeNode, WhiteoutSpec};
+use std::ffi::OsString;
+use std::fs;
+use std::path::{Path, PathBuf};
+use trees::Node;
+
+// collect full path of every node in tree, sorted
+pub fn tree_paths(tree: &FileSystemTree) -> Vec<String> {
+    fn collect(node: &Node<TreeNode>, parent: &str, paths: &mut Vec<String>) {
+        for child in node.iter() {
+            let path = format!("{}/{}", parent, escape_name(&child.data().name));
+            paths.push(path.clone());
+            collect(child, &path, paths);
+        }
+    }
+    let mut paths = Vec::new();
+    collect(tree.data.root(), "", &mut paths);
+    paths.sort();
+    paths
+}
+
+// find node by full path, e.g. "/a/file1"
+pub fn tree_node<'a>(tree: &'a FileSystemTree, path: &str) -> Option<&'a TreeNode> {
+    tree.lookup(Path::new(path)).map(|node| node.data())
+}
+
+// create an empty dir under temp dir for test
+pub fn test_dir(name: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("merge-tree-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&path);
+    fs::create_dir_all(&path).unwrap();
+    path
+}
+
+// node built in memory, whiteout of upper node is detected like scanning
+pub fn mem_node(name: &str, meta: NodeMeta, overlay: Overlay) -> TreeNode {
+    let mut node = TreeNode::new(OsString::from(name), meta, overlay, PathBuf::new());
+    if overlay != Overlay::Lower {
+        node.build_node_overlay(WhiteoutSpec::Overlayfs);
+    }
+    node
+}
+
+// append an entry to an archive built in memory, GNU header is used for
+// long names
+pub fn tar_entry(
+    builder: &mut tar::Builder<Vec<u8>>,
+    path: &str,
+    kind: tar::EntryType,
+    data: &[u8],
+) {
+    let mut header = tar_header(kind);
+    header.set_size(data.len() as u64);
+    builder.append_data(&mut header, path, data).unwrap();
+}
+
+// header of an empty entry owned by root
+pub fn tar_header(kind: tar::EntryType) -> tar::Header {
+    let mut header = tar::Header::new_gnu();
+    header.set_entry_type(kind);
+    header.set_mode(if kind.is_dir() { 0o755 } else { 0o644 });
+    header.set_uid(0);
+    header.set_gid(0);
+    header.set_mtime(0);
+    header.set_size(0);
+    header
+}
+
+// archive every layer dir of example like "tar -C dir -cf layer.tar ."
+pub fn tar_example_layer(path: &Path) -> Vec<u8> {
+    let mut builder = tar::Builder::new(Vec::new());
+    builder.follow_symlinks(false);
+    builder.append_dir_all(".", path).unwrap();
+    builder.into_inner().unwrap()
+}
+
+// archive layer dir without an entry of its root, like "tar -C dir -cf
+// layer.tar *" and many layers of real images
+pub fn tar_example_layer_without_root(path: &Path) -> Vec<u8> {
+    let mut builder = tar::Builder::new(Vec::new());
+    builder.follow_symlinks(false);
+    let mut children: Vec<PathBuf> = fs::read_dir(path)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .collect();
+    children.sort();
+    for child in children {
+        let name = child.file_name().unwrap();
+        if fs::symlink_metadata(&child).unwrap().is_dir() {
+            builder.append_dir_all(name, &child).unwrap();
+        } else {
+            builder.append_path_with_name(&child, name).unwrap();
+        }
+    }
+    builder.into_inner().unwrap()
+}
+
+// hardlinks can't be kept in git, create them before testing
+pub fn gen_hardlink_dir(name: &str, files: &[&str], links: &[(&str, &str)]) -> PathBuf {
+    let path = test_dir(name);
+    for file in files {
+        let file_path = path.join(file);
+        fs::create_dir_all(file_path.parent().unwrap()).unwrap();
+        fs::write(&file_path, b"").unwrap();
+    }
+    for (target, link) in links {
+        let link_path = path.join(link);
+        fs::create_dir_all(link_path.parent().unwrap()).unwrap();
+        fs::hard_link(path.join(target), link_path).unwrap();
+    }
+    path
+}
@@ -1,12 +1,13 @@
 use crate::build::{OCI_WHITEOUT_OPAQUE, OCI_WHITEOUT_PREFIX, OVERLAYFS_WHITEOUT_OPAQUE};
-use nix::sys::stat;
-use std::collections::HashMap;
-use std::ffi::OsString;
+use crate::error::{MergeTreeError, Result};
+use crate::meta::{FileType, NodeMeta};
+use crate::scan::{self, ScannedDirs};
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::{OsStr, OsString};
 use std::fs;
-use std::fs::Metadata;
 use std::io;
-use std::os::linux::fs::MetadataExt;
-use std::path::PathBuf;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Component, Path, PathBuf};
 use trees::{Node, Tree};
 
 #[allow(dead_code)]
@@ -56,23 +57,117 @@ impl XAttrs {
     pub fn add(&mut self, key: OsString, value: XattrValue) {
         self.pairs.insert(key, value);
     }
+
+    #[allow(dead_code)]
+    pub fn iter(&self) -> impl Iterator<Item = (&OsString, &XattrValue)> {
+        self.pairs.iter()
+    }
+
+    /// Drop every xattr whose key starts with prefix
+    pub fn remove_prefix(&mut self, prefix: &str) {
+        self.pairs
+            .retain(|key, _| !key.as_bytes().starts_with(prefix.as_bytes()));
+    }
+}
+
+/// Which xattrs to collect when scanning
+#[derive(Clone, Debug)]
+pub struct XattrOption {
+    /// Read xattrs or not, overlayfs opaque xattr of upper dirs is always read
+    /// to find opaque dirs
+    pub enabled: bool,
+    /// Only keep xattrs whose key starts with one of them, e.g. "user." or
+    /// "security.capability", all are kept if empty
+    pub include: Vec<String>,
+    /// Drop xattrs whose key starts with one of them, e.g. "security.selinux",
+    /// checked after include
+    pub exclude: Vec<String>,
+}
+
+impl Default for XattrOption {
+    fn default() -> Self {
+        XattrOption {
+            enabled: true,
+            include: Vec::new(),
+            exclude: Vec::new(),
+        }
+    }
+}
+
+impl XattrOption {
+    /// Whether to keep the xattr with key
+    pub fn keep(&self, key: &OsStr) -> bool {
+        let matches = |prefix: &String| key.as_bytes().starts_with(prefix.as_bytes());
+        self.enabled
+            && (self.include.is_empty() || self.include.iter().any(matches))
+            && !self.exclude.iter().any(matches)
+    }
+}
+
+// File system without xattr support or a namespace we may not read is not an
+// error of the layer, xattrs of the file are skipped with a warning. ENODATA is
+// an xattr removed after it is listed.
+fn skip_xattr_error(path: &Path, e: &io::Error) -> bool {
+    match e.raw_os_error() {
+        Some(libc::ENOTSUP) | Some(libc::EPERM) | Some(libc::ENODATA) => {
+            log::warn!("skip xattrs of {}: {}", escape_name(path.as_os_str()), e);
+            true
+        }
+        _ => false,
+    }
+}
+
+/// Identity of a file with several names, the same inode of the same layer
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct HardlinkKey {
+    /// Index of the layer, see TreeNode::layer
+    pub layer: usize,
+    pub dev: u64,
+    pub ino: u64,
 }
 
 // file system tree node
+#[derive(Clone)]
 pub struct TreeNode {
-    pub name: String,
-    pub meta: Metadata,
+    /// Raw file name, may not be valid UTF-8
+    pub name: OsString,
+    pub meta: NodeMeta,
     pub overlay: Overlay,
     pub xattrs: XAttrs,
+    /// Path the node is read from, on disk or in an archive
+    pub source: PathBuf,
+    /// Index of the layer the node comes from, see FileSystemTree::layers
+    pub layer: usize,
+    /// Layers which had the same path and are shadowed by this node, from
+    /// bottom to top
+    pub shadowed: Vec<usize>,
+    /// Target of symlink as it is stored, never resolved
+    pub link_target: Option<PathBuf>,
+    /// Set if the file has other names in the tree, shared by all of them
+    pub hardlink: Option<HardlinkKey>,
+    /// Entry on another file system than the layer root, scanned with
+    /// ScanOption::one_file_system. Its children are never scanned.
+    pub mount_point: bool,
+    /// Dir made up for an archive without an entry for it, like a missing
+    /// "./" or parent dir. Its meta and xattrs are not real, so merging it
+    /// keeps those of the lower dir.
+    pub implicit: bool,
 }
 
 impl TreeNode {
-    pub fn new(name: String, meta: Metadata, overlay: Overlay) -> Self {
+    pub fn new(name: OsString, meta: NodeMeta, overlay: Overlay, source: PathBuf) -> Self {
         TreeNode {
             name,
             meta,
             overlay,
             xattrs: XAttrs::new(),
+            source,
+            layer: 0,
+            shadowed: Vec::new(),
+            link_target: None,
+            hardlink: None,
+            mount_point: false,
+            implicit: false,
         }
     }
 
@@ -86,6 +181,29 @@ impl TreeNode {
         self.meta.is_file()
     }
 
+    pub fn is_symlink(&self) -> bool {
+        self.meta.is_symlink()
+    }
+
+    pub fn file_type(&self) -> FileType {
+        self.meta.file_type
+    }
+
+    /// Char or block device, see NodeMeta::major and NodeMeta::minor
+    #[allow(dead_code)]
+    pub fn is_device(&self) -> bool {
+        matches!(
+            self.file_type(),
+            FileType::CharDevice | FileType::BlockDevice
+        )
+    }
+
+    /// Device, fifo or socket
+    #[allow(dead_code)]
+    pub fn is_special_file(&self) -> bool {
+        self.is_device() || matches!(self.file_type(), FileType::Fifo | FileType::Socket)
+    }
+
     #[allow(dead_code)]
     pub fn is_whiteout(&self) -> bool {
         self.overlay == Overlay::UpperRemove || self.overlay == Overlay::UpperOpaque
@@ -105,9 +223,9 @@ impl TreeNode {
         if *spec != WhiteoutSpec::Overlayfs {
             return false;
         }
-        (self.meta.st_mode() & libc::S_IFMT == libc::S_IFCHR)
-            && stat::major(self.meta.st_rdev()) == 0
-            && stat::minor(self.meta.st_rdev()) == 0
+        self.meta.file_type == FileType::CharDevice
+            && self.meta.major() == 0
+            && self.meta.minor() == 0
     }
 
     pub fn is_overlayfs_opaque(&self, spec: &WhiteoutSpec) -> bool {
@@ -125,14 +243,39 @@ impl TreeNode {
         false
     }
 
-    pub fn build_node_xattrs(&mut self, path: PathBuf) -> io::Result<()> {
-        let mut xattrs = xattr::list(path.clone()).unwrap().peekable();
-        if xattrs.peek().is_none() {
+    pub fn build_node_xattrs(&mut self, path: PathBuf, option: &XattrOption) -> Result<()> {
+        if !option.enabled {
             return Ok(());
         }
+        let xattrs = match xattr::list(&path) {
+            Ok(xattrs) => xattrs,
+            Err(e) if skip_xattr_error(&path, &e) => return Ok(()),
+            Err(e) => return Err(MergeTreeError::xattr(&path, e)),
+        };
         for attr_key in xattrs {
-            let value = xattr::get(path.clone(), &attr_key)?;
-            self.xattrs.add(attr_key, value.unwrap_or_default())
+            if !option.keep(&attr_key) {
+                continue;
+            }
+            match xattr::get(&path, &attr_key) {
+                Ok(value) => self.xattrs.add(attr_key, value.unwrap_or_default()),
+                Err(e) if skip_xattr_error(&path, &e) => continue,
+                Err(e) => return Err(MergeTreeError::xattr(&path, e)),
+            }
+        }
+        Ok(())
+    }
+
+    /// Read one xattr whatever XattrOption is, if it is not read yet
+    pub fn build_node_xattr(&mut self, path: PathBuf, key: &str) -> Result<()> {
+        let key = OsString::from(key);
+        if self.xattrs.get(&key).is_some() {
+            return Ok(());
+        }
+        match xattr::get(&path, &key) {
+            Ok(Some(value)) => self.xattrs.add(key, value),
+            Ok(None) => {}
+            Err(e) if skip_xattr_error(&path, &e) => {}
+            Err(e) => return Err(MergeTreeError::xattr(&path, e)),
         }
         Ok(())
     }
@@ -140,10 +283,10 @@ impl TreeNode {
     pub fn build_node_overlay(&mut self, whiteout_spec: WhiteoutSpec) {
         if whiteout_spec == WhiteoutSpec::Oci {
             if self.name == OCI_WHITEOUT_OPAQUE {
-                println!("handle oci opaque");
+                log::debug!("handle oci opaque {:?}", self.source);
                 self.overlay = Overlay::UpperOpaque;
-            } else if self.name.starts_with(OCI_WHITEOUT_PREFIX) {
-                println!("handle oci whiteout");
+            } else if self.has_oci_whiteout_prefix() {
+                log::debug!("handle oci whiteout {:?}", self.source);
                 self.overlay = Overlay::UpperRemove;
             }
             return;
@@ -151,15 +294,22 @@ impl TreeNode {
 
         if whiteout_spec == WhiteoutSpec::Overlayfs {
             if self.is_overlayfs_whiteout(&whiteout_spec) {
-                println!("handle overlayfs whiteout");
+                log::debug!("handle overlayfs whiteout {:?}", self.source);
                 self.overlay = Overlay::UpperRemove;
             } else if self.is_directory() && self.is_overlayfs_opaque(&whiteout_spec) {
-                println!("handle overlayfs opaque");
+                log::debug!("handle overlayfs opaque {:?}", self.source);
                 self.overlay = Overlay::UpperOpaque;
             }
         }
     }
 
+    // whiteout prefix is matched on raw bytes, the rest of name may be any bytes
+    fn has_oci_whiteout_prefix(&self) -> bool {
+        self.name
+            .as_bytes()
+            .starts_with(OCI_WHITEOUT_PREFIX.as_bytes())
+    }
+
     /// Get real whiteout type by spec, oci or overlayfs
     pub fn whiteout_type(&self, spec: &WhiteoutSpec) -> Option<WhiteoutType> {
         if self.overlay == Overlay::Lower {
@@ -170,7 +320,7 @@ impl TreeNode {
             WhiteoutSpec::Oci => {
                 if self.name == OCI_WHITEOUT_OPAQUE {
                     return Some(WhiteoutType::OciOpaque);
-                } else if self.name.starts_with(OCI_WHITEOUT_PREFIX) {
+                } else if self.has_oci_whiteout_prefix() {
                     return Some(WhiteoutType::OciRemoval);
                 }
             }
@@ -187,87 +337,788 @@ impl TreeNode {
     }
 }
 
+/// Order of children of every dir in FileSystemTree
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ChildOrder {
+    /// Byte-wise sorted by name, reproducible between file systems and runs
+    Sorted,
+    /// Order the children are read in or added in
+    Insertion,
+}
+
+/// Options to scan a dir into FileSystemTree
+#[derive(Clone, Debug)]
+pub struct ScanOption {
+    pub child_order: ChildOrder,
+    /// Number of threads to scan dirs, the tree is the same whatever it is
+    pub threads: usize,
+    /// Fail the scan if an entry is deeper than it, children of root are 1
+    pub max_depth: Option<usize>,
+    /// Don't go into entries on other file systems than the layer root, like
+    /// bind mounts or /proc, they are kept as mount points
+    pub one_file_system: bool,
+    pub xattrs: XattrOption,
+}
+
+impl Default for ScanOption {
+    fn default() -> Self {
+        ScanOption {
+            child_order: ChildOrder::Sorted,
+            threads: 1,
+            max_depth: None,
+            one_file_system: false,
+            xattrs: XattrOption::default(),
+        }
+    }
+}
+
+/// Options to display FileSystemTree
+#[derive(Clone, Debug, Default)]
+pub struct DisplayOption {
+    /// Show which layer every node comes from and which layers it shadows
+    pub show_layer: bool,
+}
+
 pub struct FileSystemTree {
     pub data: Tree<TreeNode>,
+    pub child_order: ChildOrder,
+    /// Path of every layer merged into the tree, indexed by TreeNode::layer,
+    /// the first one is the base
+    pub layers: Vec<PathBuf>,
+}
+
+impl Drop for FileSystemTree {
+    fn drop(&mut self) {
+        while let Some(child) = self.data.pop_front() {
+            drop_tree(child);
+        }
+    }
 }
 
 impl FileSystemTree {
+    /// Tree with root only, nodes are added by insert, e.g. to build a tree
+    /// from an archive or in test
+    #[allow(dead_code)]
+    pub fn new(root: TreeNode, layer_path: PathBuf, child_order: ChildOrder) -> Self {
+        FileSystemTree {
+            data: Tree::new(root),
+            child_order,
+            layers: vec![layer_path],
+        }
+    }
+
+    /// Add node with full path from root, its parent dir must be in the tree
+    /// already, return false if not
+    #[allow(dead_code)]
+    pub fn insert(&mut self, path: &Path, node: TreeNode) -> bool {
+        let child_order = self.child_order;
+        let parent = match path.parent().and_then(|parent| self.lookup_mut(parent)) {
+            Some(parent) if parent.data().is_directory() => parent,
+            _ => return false,
+        };
+        Self::insert_child(parent, Tree::new(node), child_order);
+        true
+    }
+
+    #[allow(dead_code)]
     pub fn build_from_file_system(
         path: PathBuf,
         overlay: Overlay,
         whiteout_spec: WhiteoutSpec,
-    ) -> io::Result<FileSystemTree> {
-        // Got metadata
-        let meta = fs::metadata(path.clone())?;
+    ) -> Result<FileSystemTree> {
+        Self::build_from_file_system_with_option(
+            path,
+            overlay,
+            whiteout_spec,
+            &ScanOption::default(),
+        )
+    }
+
+    pub fn build_from_file_system_with_option(
+        path: PathBuf,
+        overlay: Overlay,
+        whiteout_spec: WhiteoutSpec,
+        option: &ScanOption,
+    ) -> Result<FileSystemTree> {
+        let mut trees =
+            Self::build_layers_from_file_system(vec![path], overlay, whiteout_spec, option)?;
+        Ok(trees.remove(0))
+    }
+
+    /// Build a layer from a dir or a tar archive file
+    pub fn build_layer(
+        path: PathBuf,
+        overlay: Overlay,
+        whiteout_spec: WhiteoutSpec,
+        option: &ScanOption,
+    ) -> Result<FileSystemTree> {
+        let mut trees = Self::build_layers(vec![path], overlay, whiteout_spec, option)?;
+        Ok(trees.remove(0))
+    }
+
+    /// Build every layer into its own tree in order, a layer is a dir or a
+    /// tar archive file. Dirs are scanned together, see
+    /// build_layers_from_file_system.
+    pub fn build_layers(
+        paths: Vec<PathBuf>,
+        overlay: Overlay,
+        whiteout_spec: WhiteoutSpec,
+        option: &ScanOption,
+    ) -> Result<Vec<FileSystemTree>> {
+        // a path which can't be read is left to the dir scan to report
+        let archives: Vec<bool> = paths
+            .iter()
+            .map(|path| fs::metadata(path).is_ok_and(|meta| meta.is_file()))
+            .collect();
+        let dirs = paths
+            .iter()
+            .zip(&archives)
+            .filter(|(_, archive)| !**archive)
+            .map(|(path, _)| path.clone())
+            .collect();
+        let mut dir_trees =
+            Self::build_layers_from_file_system(dirs, overlay, whiteout_spec, option)?.into_iter();
+        let mut trees = Vec::with_capacity(paths.len());
+        for (path, archive) in paths.into_iter().zip(archives) {
+            if archive {
+                trees.push(Self::build_from_tar_file(
+                    path,
+                    overlay,
+                    whiteout_spec,
+                    option,
+                )?);
+            } else {
+                trees.push(dir_trees.next().unwrap());
+            }
+        }
+        Ok(trees)
+    }
+
+    /// Scan every layer into its own tree, dirs of all layers are scanned by
+    /// the same pool of workers concurrently
+    pub fn build_layers_from_file_system(
+        paths: Vec<PathBuf>,
+        overlay: Overlay,
+        whiteout_spec: WhiteoutSpec,
+        option: &ScanOption,
+    ) -> Result<Vec<FileSystemTree>> {
+        let mut roots = Vec::with_capacity(paths.len());
+        for path in &paths {
+            roots.push(Self::scan_root(path, overlay, whiteout_spec, option)?);
+        }
+        let jobs = paths
+            .iter()
+            .cloned()
+            .zip(roots.iter().map(|root| root.meta.dev))
+            .collect();
+        let mut dirs = scan::scan_dirs(jobs, overlay, whiteout_spec, option)?;
+
+        let mut trees = Vec::with_capacity(paths.len());
+        for (id, (root, path)) in roots.into_iter().zip(paths).enumerate() {
+            let data = Self::assemble_subtree(Tree::new(root), id, &mut dirs);
+            let mut tree = FileSystemTree {
+                data,
+                child_order: option.child_order,
+                layers: vec![path],
+            };
+            // other names of a hardlink may be out of the layer
+            tree.prune_hardlinks();
+            trees.push(tree);
+        }
+        Ok(trees)
+    }
+
+    fn scan_root(
+        path: &Path,
+        overlay: Overlay,
+        whiteout_spec: WhiteoutSpec,
+        option: &ScanOption,
+    ) -> Result<TreeNode> {
+        // Got metadata, layer root itself may be a symlink to the real dir
+        let meta = fs::metadata(path).map_err(|e| MergeTreeError::io(path, e))?;
+        if !meta.is_dir() {
+            return Err(MergeTreeError::UnsupportedFileType {
+                path: path.to_path_buf(),
+                reason: "layer root is not a directory".to_string(),
+            });
+        }
         // Root dir replace /
-        let mut node = TreeNode::new("/".to_string(), meta, overlay);
+        let mut node = TreeNode::new(
+            OsString::from("/"),
+            NodeMeta::from(&meta),
+            overlay,
+            path.to_path_buf(),
+        );
         // Build node xattrs
-        node.build_node_xattrs(path.clone())?;
-        let mut data = Tree::new(node);
-        Self::build_file_system_subtree(&mut data, path, overlay, whiteout_spec)?;
-        Ok(FileSystemTree { data })
+        node.build_node_xattrs(path.to_path_buf(), &option.xattrs)?;
+        // Root dir may be overlayfs opaque too
+        if overlay != Overlay::Lower {
+            if whiteout_spec == WhiteoutSpec::Overlayfs {
+                node.build_node_xattr(path.to_path_buf(), OVERLAYFS_WHITEOUT_OPAQUE)?;
+            }
+            node.build_node_overlay(whiteout_spec);
+        }
+        Ok(node)
     }
 
-    pub fn build_file_system_subtree(
-        root: &mut Tree<TreeNode>,
-        path: PathBuf,
+    // add children scanned for dir id under root, and their subtrees. A dir
+    // is pushed to its parent once all its children are added, with a stack
+    // instead of recursion for very deep trees.
+    pub fn assemble_subtree(
+        root: Tree<TreeNode>,
+        id: usize,
+        dirs: &mut ScannedDirs,
+    ) -> Tree<TreeNode> {
+        let children = dirs.remove(&id).unwrap_or_default().into_iter();
+        let mut stack = vec![(root, children)];
+        loop {
+            let (tree, children) = stack.last_mut().unwrap();
+            match children.next() {
+                Some((node, Some(child_id))) => {
+                    let children = dirs.remove(&child_id).unwrap_or_default().into_iter();
+                    stack.push((Tree::new(node), children));
+                }
+                Some((node, None)) => tree.push_back(Tree::new(node)),
+                None => {
+                    let (tree, _) = stack.pop().unwrap();
+                    match stack.last_mut() {
+                        Some((parent, _)) => parent.push_back(tree),
+                        None => return tree,
+                    }
+                }
+            }
+        }
+    }
+
+    /// Scan children of a dir into nodes in the order of ScanOption, without
+    /// going into subdirs. root_dev is the device of the layer root to find
+    /// mount points.
+    pub fn scan_dir(
+        path: &Path,
+        root_dev: u64,
         overlay: Overlay,
         whiteout_spec: WhiteoutSpec,
-    ) -> io::Result<()> {
-        if path.is_dir() {
-            for entry in fs::read_dir(path)? {
-                //1 go entry path and filename
-                let entry = entry?;
-                let entry_path = entry.path();
-                let metadata = fs::metadata(entry_path.clone())?;
-                let file_name = entry_path.file_name().unwrap().to_str().unwrap();
-                //2. create node
-                let mut node = TreeNode::new(String::from(file_name), metadata, overlay);
-                // 2.1 build node xattr
-                node.build_node_xattrs(entry_path.clone())?;
-                // 2.2 build node whiteout
-                if overlay != Overlay::Lower {
-                    node.build_node_overlay(whiteout_spec);
+        option: &ScanOption,
+    ) -> Result<Vec<TreeNode>> {
+        let mut entries = fs::read_dir(path)
+            .and_then(|dir| dir.collect::<io::Result<Vec<_>>>())
+            .map_err(|e| MergeTreeError::io(path, e))?;
+        if option.child_order == ChildOrder::Sorted {
+            entries.sort_by(|a, b| a.file_name().as_bytes().cmp(b.file_name().as_bytes()));
+        }
+        let mut nodes = Vec::with_capacity(entries.len());
+        for entry in entries {
+            //1 go entry path and filename
+            let entry_path = entry.path();
+            // never follow symlink, a link to dir is not scanned into and
+            // a dangling link is kept as it is
+            let metadata = fs::symlink_metadata(&entry_path)
+                .map_err(|e| MergeTreeError::io(&entry_path, e))?;
+            let file_name = entry.file_name();
+            let is_dir = metadata.is_dir();
+            //2. create node
+            let mut node = TreeNode::new(
+                file_name,
+                NodeMeta::from(&metadata),
+                overlay,
+                entry_path.clone(),
+            );
+            if node.is_symlink() {
+                node.link_target = Some(
+                    fs::read_link(&entry_path).map_err(|e| MergeTreeError::io(&entry_path, e))?,
+                );
+            }
+            // dir can't be hardlinked, other names of the file are matched
+            // by inode after the whole tree is scanned
+            if !is_dir && node.meta.nlink > 1 {
+                node.hardlink = Some(HardlinkKey {
+                    layer: 0,
+                    dev: node.meta.dev,
+                    ino: node.meta.ino,
+                });
+            }
+            // entry on another file system is a mount point, it is kept as a
+            // boundary and nothing of the mounted file system is read
+            if option.one_file_system && node.meta.dev != root_dev {
+                node.mount_point = true;
+                nodes.push(node);
+                continue;
+            }
+            // 2.1 build node xattr
+            node.build_node_xattrs(entry_path.clone(), &option.xattrs)?;
+            // 2.2 build node whiteout, overlayfs opaque xattr is read even if
+            // it is filtered out
+            if overlay != Overlay::Lower {
+                if whiteout_spec == WhiteoutSpec::Overlayfs && is_dir {
+                    node.build_node_xattr(entry_path, OVERLAYFS_WHITEOUT_OPAQUE)?;
                 }
-                let mut new_tree = Tree::new(node);
-
-                if entry_path.is_dir() {
-                    let _ = Self::build_file_system_subtree(
-                        &mut new_tree,
-                        entry_path,
-                        overlay,
-                        whiteout_spec,
-                    );
+                node.build_node_overlay(whiteout_spec);
+            }
+            nodes.push(node);
+        }
+        Ok(nodes)
+    }
+
+    /// Add a subtree as child of node, keeping the child order
+    pub fn insert_child(node: &mut Node<TreeNode>, child: Tree<TreeNode>, child_order: ChildOrder) {
+        if child_order == ChildOrder::Sorted {
+            let name = child.root().data().name.as_bytes();
+            if let Some(mut next) = node
+                .iter_mut()
+                .find(|sibling| sibling.data().name.as_bytes() > name)
+            {
+                next.insert_prev_sib(child);
+                return;
+            }
+        }
+        node.push_back(child);
+    }
+
+    /// Travel every node with its full path from root in pre-order
+    pub fn walk<F: FnMut(&Path, &Node<TreeNode>)>(&self, mut f: F) {
+        let mut stack = vec![(self.data.root(), PathBuf::from("/"))];
+        while let Some((node, path)) = stack.pop() {
+            f(&path, node);
+            // reversed to pop the first child first
+            let children: Vec<_> = node.iter().collect();
+            for child in children.into_iter().rev() {
+                stack.push((child, path.join(&child.data().name)));
+            }
+        }
+    }
+
+    /// Names of every file with more than one name in the tree, in pre-order
+    pub fn hardlink_groups(&self) -> BTreeMap<HardlinkKey, Vec<PathBuf>> {
+        let mut groups: BTreeMap<HardlinkKey, Vec<PathBuf>> = BTreeMap::new();
+        self.walk(|path, node| {
+            if let Some(key) = node.data().hardlink {
+                groups.entry(key).or_default().push(path.to_path_buf());
+            }
+        });
+        groups.retain(|_, paths| paths.len() > 1);
+        groups
+    }
+
+    /// Drop hardlink key of files left with only one name in the tree, e.g.
+    /// after the other names are removed by whiteout or replaced by upper layer
+    pub fn prune_hardlinks(&mut self) {
+        let groups = self.hardlink_groups();
+        let mut stack = vec![self.data.root_mut().get_mut()];
+        while let Some(node) = stack.pop() {
+            if let Some(key) = node.data().hardlink {
+                if !groups.contains_key(&key) {
+                    node.data_mut().hardlink = None;
                 }
-                //3. push back to root
-                root.push_back(new_tree);
             }
+            stack.extend(node.iter_mut().map(|child| child.get_mut()));
         }
-        Ok(())
     }
 
+    /// Find node by its full path from root, like "/usr/bin/foo"
+    #[allow(dead_code)]
+    pub fn lookup(&self, path: &Path) -> Option<&Node<TreeNode>> {
+        let mut node = self.data.root();
+        for component in path.components() {
+            if let Component::Normal(name) = component {
+                node = node
+                    .iter()
+                    .find(|child| child.data().name.as_os_str() == name)?;
+            }
+        }
+        Some(node)
+    }
+
+    /// Find node by its full path from root to edit it
+    #[allow(dead_code)]
+    pub fn lookup_mut(&mut self, path: &Path) -> Option<&mut Node<TreeNode>> {
+        let mut node = self.data.root_mut().get_mut();
+        for component in path.components() {
+            if let Component::Normal(name) = component {
+                node = node
+                    .iter_mut()
+                    .find(|child| child.data().name.as_os_str() == name)?
+                    .get_mut();
+            }
+        }
+        Some(node)
+    }
+
+    /// Path of the layer with index, see TreeNode::layer
+    pub fn layer_path(&self, layer: usize) -> Option<&Path> {
+        self.layers.get(layer).map(|path| path.as_path())
+    }
+
+    #[allow(dead_code)]
     pub fn display_file_tree(&self) {
-        let level = 0;
-        println!("{}", self.data.root().data().name);
-        for node in self.data.iter() {
-            Self::display_file_tree_inner(node, level);
+        self.display_file_tree_with_option(&DisplayOption::default())
+    }
+
+    pub fn display_file_tree_with_option(&self, option: &DisplayOption) {
+        print!("{}", self.format_file_tree_with_option(option));
+    }
+
+    /// Render the tree as text, one line per node
+    #[allow(dead_code)]
+    pub fn format_file_tree(&self) -> String {
+        self.format_file_tree_with_option(&DisplayOption::default())
+    }
+
+    pub fn format_file_tree_with_option(&self, option: &DisplayOption) -> String {
+        let root = self.data.root();
+        let mut out = format!(
+            "{}{}\n",
+            escape_name(&root.data().name),
+            self.format_layer(root.data(), option)
+        );
+        // the first name of a hardlink group, other names are shown to link to it
+        let hardlinks: HashMap<HardlinkKey, PathBuf> = self
+            .hardlink_groups()
+            .into_iter()
+            .map(|(key, mut paths)| (key, paths.swap_remove(0)))
+            .collect();
+        let mut stack: Vec<_> = self
+            .data
+            .iter()
+            .map(|node| (node, Path::new("/").join(&node.data().name), 0))
+            .collect();
+        stack.reverse();
+        while let Some((node, path, level)) = stack.pop() {
+            self.format_node(node, &path, level, option, &hardlinks, &mut out);
+            // reversed to pop the first child first
+            let children: Vec<_> = node.iter().collect();
+            for child in children.into_iter().rev() {
+                stack.push((child, path.join(&child.data().name), level + 1));
+            }
+        }
+        out
+    }
+
+    // format one line of node, children are formatted by caller
+    fn format_node(
+        &self,
+        node: &Node<TreeNode>,
+        path: &Path,
+        level: usize,
+        option: &DisplayOption,
+        hardlinks: &HashMap<HardlinkKey, PathBuf>,
+        out: &mut String,
+    ) {
+        let prefix = format!("{}├──", "│  ".repeat(level));
+
+        let mut name = escape_name(&node.data().name);
+        if let Some(target) = &node.data().link_target {
+            name.push_str(&format!(" -> {}", escape_name(target.as_os_str())));
+        }
+        if let Some(first) = node.data().hardlink.and_then(|key| hardlinks.get(&key)) {
+            if first != path {
+                name.push_str(&format!(" => {}", escape_name(first.as_os_str())));
+            }
+        }
+        if node.data().mount_point {
+            name.push_str(" [mount point]");
+        }
+        let meta = &node.data().meta;
+        match meta.file_type {
+            FileType::CharDevice => {
+                name.push_str(&format!(" [char {}:{}]", meta.major(), meta.minor()))
+            }
+            FileType::BlockDevice => {
+                name.push_str(&format!(" [block {}:{}]", meta.major(), meta.minor()))
+            }
+            FileType::Fifo => name.push_str(" [fifo]"),
+            FileType::Socket => name.push_str(" [socket]"),
+            _ => {}
         }
+        out.push_str(&format!(
+            "{} {}{}\n",
+            prefix,
+            name,
+            self.format_layer(node.data(), option)
+        ));
     }
 
-    fn display_file_tree_inner(node: &Node<TreeNode>, level: usize) {
-        let mut prefix = "├──".to_string();
-        let mut i = 0;
-        while i < level {
-            prefix = format!("│  {}", prefix);
-            i += 1;
+    // e.g. " [layer 2 ./upper2] [shadows 0 1]"
+    fn format_layer(&self, node: &TreeNode, option: &DisplayOption) -> String {
+        if !option.show_layer {
+            return String::new();
+        }
+        let mut out = format!(" [layer {}", node.layer);
+        if let Some(path) = self.layer_path(node.layer) {
+            out.push_str(&format!(" {}", path.display()));
+        }
+        out.push(']');
+        if !node.shadowed.is_empty() {
+            let shadowed: Vec<String> = node.shadowed.iter().map(|l| l.to_string()).collect();
+            out.push_str(&format!(" [shadows {}]", shadowed.join(" ")));
+        }
+        out
+    }
+}
+
+/// Drop a tree with a stack, Tree drops children by recursion which can
+/// overflow the stack for a very deep tree
+pub fn drop_tree(tree: Tree<TreeNode>) {
+    let mut stack = vec![tree];
+    while let Some(mut tree) = stack.pop() {
+        while let Some(child) = tree.pop_front() {
+            stack.push(child);
+        }
+    }
+}
+
+/// Render a raw file name as printable text, bytes which are not valid UTF-8
+/// or are control characters are escaped as "\xNN", and "\" as "\\"
+pub fn escape_name(name: &OsStr) -> String {
+    let mut out = String::new();
+    let mut bytes = name.as_bytes();
+    while !bytes.is_empty() {
+        let (valid, invalid) = match std::str::from_utf8(bytes) {
+            Ok(s) => (s, 0),
+            Err(e) => (
+                // bytes before the error are checked valid by from_utf8 above
+                std::str::from_utf8(&bytes[..e.valid_up_to()]).unwrap(),
+                e.error_len().unwrap_or(bytes.len() - e.valid_up_to()),
+            ),
+        };
+        for c in valid.chars() {
+            if c == '\\' {
+                out.push_str("\\\\");
+            } else if c.is_control() {
+                for b in c.to_string().bytes() {
+                    out.push_str(&format!("\\x{:02x}", b));
+                }
+            } else {
+                out.push(c);
+            }
+        }
+        for b in &bytes[valid.len()..valid.len() + invalid] {
+            out.push_str(&format!("\\x{:02x}", b));
         }
+        bytes = &bytes[valid.len() + invalid..];
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::MergeTreeError;
+    use crate::test_util::{gen_hardlink_dir, test_dir, tree_node, tree_paths};
+    use crate::tree::{FileSystemTree, Overlay, ScanOption, WhiteoutSpec, XattrOption};
+    use std::ffi::OsString;
+    use std::fs;
+    use std::os::unix::fs::MetadataExt;
+    use std::path::{Path, PathBuf};
+
+    #[test]
+    fn test_hardlink_scan() {
+        let path = gen_hardlink_dir(
+            "hardlink-scan",
+            &["a/file1", "b/file2"],
+            &[("a/file1", "b/file3"), ("a/file1", "file4")],
+        );
+        let tree = FileSystemTree::build_from_file_system(path, Overlay::Lower, WhiteoutSpec::Oci)
+            .unwrap();
+        let groups: Vec<Vec<PathBuf>> = tree.hardlink_groups().into_values().collect();
+        assert_eq!(
+            groups,
+            vec![vec![
+                PathBuf::from("/a/file1"),
+                PathBuf::from("/b/file3"),
+                PathBuf::from("/file4"),
+            ]]
+        );
+        assert!(tree_node(&tree, "/b/file2").unwrap().hardlink.is_none());
+        // other names link to the first one
+        assert_eq!(
+            tree.format_file_tree(),
+            "/
+├── a
+│  ├── file1
+├── b
+│  ├── file2
+│  ├── file3 => /a/file1
+├── file4 => /a/file1
+"
+        );
+    }
+
+    #[test]
+    fn test_scan_error() {
+        let missing = PathBuf::from("./file-example/not-exist");
+        let err =
+            FileSystemTree::build_from_file_system(missing, Overlay::Lower, WhiteoutSpec::Oci)
+                .err()
+                .unwrap();
+        assert!(matches!(err, MergeTreeError::Io { .. }));
+        assert!(err.to_string().contains("file-example/not-exist"));
+
+        let file = PathBuf::from("./file-example/example1/base-dir/a/file1");
+        let err = FileSystemTree::build_from_file_system(file, Overlay::Lower, WhiteoutSpec::Oci)
+            .err()
+            .unwrap();
+        assert!(matches!(err, MergeTreeError::UnsupportedFileType { .. }));
+        assert_eq!(err.exit_code(), 4);
+    }
+
+    #[test]
+    fn test_scan_max_depth() {
+        let path = PathBuf::from("./file-example/example1/base-dir");
+        let option = ScanOption {
+            max_depth: Some(1),
+            ..ScanOption::default()
+        };
+        let err = FileSystemTree::build_from_file_system_with_option(
+            path.clone(),
+            Overlay::Lower,
+            WhiteoutSpec::Oci,
+            &option,
+        )
+        .err()
+        .unwrap();
+        assert!(matches!(err, MergeTreeError::TooDeep { max_depth: 1, .. }));
+        assert_eq!(err.exit_code(), 7);
 
-        println!("{} {}", prefix, node.data().name,);
+        // /a/a/file1 is the deepest
+        let option = ScanOption {
+            max_depth: Some(3),
+            ..ScanOption::default()
+        };
+        assert!(FileSystemTree::build_from_file_system_with_option(
+            path,
+            Overlay::Lower,
+            WhiteoutSpec::Oci,
+            &option
+        )
+        .is_ok());
+    }
 
-        if !node.has_no_child() {
-            for child in node.iter() {
-                Self::display_file_tree_inner(child, level + 1);
+    #[test]
+    fn test_one_file_system_scan() {
+        use nix::mount::{self, MsFlags};
+        let layer = test_dir("one-file-system");
+        fs::write(layer.join("file1"), b"").unwrap();
+        let mnt = layer.join("mnt");
+        fs::create_dir(&mnt).unwrap();
+        let option = ScanOption {
+            one_file_system: true,
+            ..ScanOption::default()
+        };
+
+        // entry on another device than the layer root is a mount point
+        let dev = fs::metadata(&layer).unwrap().dev();
+        let nodes =
+            FileSystemTree::scan_dir(&layer, dev + 1, Overlay::Lower, WhiteoutSpec::Oci, &option)
+                .unwrap();
+        assert!(nodes.iter().all(|node| node.mount_point));
+        let nodes =
+            FileSystemTree::scan_dir(&layer, dev, Overlay::Lower, WhiteoutSpec::Oci, &option)
+                .unwrap();
+        assert!(nodes.iter().all(|node| !node.mount_point));
+
+        // a tmpfs mounted in the layer, mounting needs CAP_SYS_ADMIN
+        if mount::mount(
+            Some("tmpfs"),
+            &mnt,
+            Some("tmpfs"),
+            MsFlags::empty(),
+            None::<&str>,
+        )
+        .is_err()
+        {
+            println!("no permission to mount tmpfs, skip");
+            return;
+        }
+        fs::write(mnt.join("file2"), b"").unwrap();
+        let scan = |option: &ScanOption| {
+            FileSystemTree::build_from_file_system_with_option(
+                layer.clone(),
+                Overlay::Lower,
+                WhiteoutSpec::Oci,
+                option,
+            )
+        };
+        let tree = scan(&option);
+        let full_tree = scan(&ScanOption::default());
+        mount::umount(&mnt).unwrap();
+        let tree = tree.unwrap();
+        let mnt = tree.lookup(Path::new("/mnt")).unwrap();
+        assert!(mnt.data().mount_point);
+        assert!(mnt.has_no_child());
+        assert!(tree.format_file_tree().contains("├── mnt [mount point]\n"));
+        assert!(!tree_node(&tree, "/file1").unwrap().mount_point);
+        assert_eq!(
+            tree_paths(&full_tree.unwrap()),
+            vec!["/file1", "/mnt", "/mnt/file2"]
+        );
+    }
+
+    #[test]
+    fn test_xattr_filter() {
+        let path = test_dir("xattr-filter");
+        let file = path.join("file1");
+        fs::write(&file, b"").unwrap();
+        for (key, value) in [
+            ("user.keep", b"1"),
+            ("user.drop", b"2"),
+            ("trusted.other", b"3"),
+        ] {
+            if xattr::set(&file, key, value).is_err() {
+                println!("no permission to set xattr, skip");
+                return;
             }
         }
+        let scan = |xattrs: XattrOption| {
+            let option = ScanOption {
+                xattrs,
+                ..ScanOption::default()
+            };
+            let tree = FileSystemTree::build_from_file_system_with_option(
+                path.clone(),
+                Overlay::Lower,
+                WhiteoutSpec::Oci,
+                &option,
+            )
+            .unwrap();
+            let mut keys: Vec<OsString> = tree_node(&tree, "/file1")
+                .unwrap()
+                .xattrs
+                .iter()
+                .map(|(key, _)| key.clone())
+                .collect();
+            keys.sort();
+            keys
+        };
+
+        assert_eq!(
+            scan(XattrOption::default()),
+            vec!["trusted.other", "user.drop", "user.keep"]
+        );
+        assert_eq!(
+            scan(XattrOption {
+                include: vec!["user.".to_string()],
+                exclude: vec!["user.drop".to_string()],
+                ..XattrOption::default()
+            }),
+            vec!["user.keep"]
+        );
+        assert!(scan(XattrOption {
+            enabled: false,
+            ..XattrOption::default()
+        })
+        .is_empty());
+    }
+
+    #[test]
+    fn test_xattr_unsupported() {
+        // procfs has no xattr support, reading overlayfs opaque xattr of root
+        // fails with ENOTSUP and is skipped
+        let path = PathBuf::from("/proc/sys/kernel/random");
+        if !path.is_dir() {
+            return;
+        }
+        let tree =
+            FileSystemTree::build_from_file_system(path, Overlay::None, WhiteoutSpec::Overlayfs)
+                .unwrap();
+        assert!(tree_node(&tree, "/uuid").is_some());
     }
 }